@@ -0,0 +1,191 @@
+use std::path::PathBuf;
+
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum OutputFormat {
+    // Human-readable "Warning: ..." / "Error: ..." lines.
+    Plain,
+    // One JSON object per line, for build wrappers to parse.
+    Json,
+    // GitHub Actions workflow commands, which show up as annotations on the diff.
+    Github
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Level {
+    // Information that isn't a problem, such as the profile summary.
+    Note,
+    Warning,
+    Error
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Diagnostic {
+    pub(crate) level: Level,
+    pub(crate) message: String,
+    // Where the problem is, relative to the book root, when it's tied to a chapter.
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) line: Option<usize>
+}
+
+impl OutputFormat {
+    // The `output-format` values, and the format each one selects.
+    pub(crate) const CHOICES: [(&'static str, OutputFormat); 3] = [("plain", OutputFormat::Plain), ("json", OutputFormat::Json), ("github", OutputFormat::Github)];
+}
+
+impl Level {
+    fn label(&self) -> &'static str {
+        match self {
+            Level::Note => "note",
+            Level::Warning => "warning",
+            Level::Error => "error"
+        }
+    }
+}
+
+impl Diagnostic {
+    pub(crate) fn note(message: String) -> Diagnostic {
+        Diagnostic { level: Level::Note, message, file: None, line: None }
+    }
+
+    pub(crate) fn warning(message: String) -> Diagnostic {
+        Diagnostic { level: Level::Warning, message, file: None, line: None }
+    }
+
+    pub(crate) fn error(message: String) -> Diagnostic {
+        Diagnostic { level: Level::Error, message, file: None, line: None }
+    }
+
+    pub(crate) fn at(mut self, file: PathBuf, line: usize) -> Diagnostic {
+        self.file = Some(file);
+        self.line = Some(line);
+        self
+    }
+
+    pub(crate) fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Plain => {
+                let label = match self.level {
+                    Level::Note => "Note",
+                    Level::Warning => "Warning",
+                    Level::Error => "Error"
+                };
+                match (&self.file, self.line) {
+                    (Some(file), Some(line)) => format!("{}: {}:{}: {}", label, file.display(), line, self.message),
+                    _ => format!("{}: {}", label, self.message)
+                }
+            },
+            OutputFormat::Json => {
+                let mut object = json!({
+                    "level": self.level.label(),
+                    "message": self.message
+                });
+                if let Some(file) = &self.file {
+                    object["file"] = json!(file.to_string_lossy());
+                }
+                if let Some(line) = self.line {
+                    object["line"] = json!(line);
+                }
+                object.to_string()
+            },
+            OutputFormat::Github => {
+                // GitHub calls its informational level `notice`.
+                let command = match self.level {
+                    Level::Note => "notice",
+                    level => level.label()
+                };
+                let mut properties: Vec<String> = Vec::new();
+                if let Some(file) = &self.file {
+                    properties.push(format!("file={}", escape_github_property(&file.to_string_lossy())));
+                }
+                if let Some(line) = self.line {
+                    properties.push(format!("line={}", line));
+                }
+                if properties.is_empty() {
+                    format!("::{}::{}", command, escape_github_data(&self.message))
+                } else {
+                    format!("::{} {}::{}", command, properties.join(","), escape_github_data(&self.message))
+                }
+            }
+        }
+    }
+}
+
+// Escaping rules for workflow command messages.
+fn escape_github_data(value: &str) -> String {
+    value.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+// Property values additionally can't contain the `:` and `,` delimiters.
+fn escape_github_property(value: &str) -> String {
+    escape_github_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::diagnostics::{Diagnostic, OutputFormat};
+
+    #[test]
+    fn test_render_plain() {
+        let diagnostic = Diagnostic::warning("Something odd".to_string());
+
+        assert_eq!(diagnostic.render(OutputFormat::Plain), "Warning: Something odd");
+    }
+
+    #[test]
+    fn test_render_json() {
+        let diagnostic = Diagnostic::error("Something \"broke\"".to_string());
+
+        assert_eq!(diagnostic.render(OutputFormat::Json), r#"{"level":"error","message":"Something \"broke\""}"#);
+    }
+
+    #[test]
+    fn test_render_plain_with_location() {
+        let diagnostic = Diagnostic::error("Missing".to_string()).at(PathBuf::from("src/foo.md"), 3);
+
+        assert_eq!(diagnostic.render(OutputFormat::Plain), "Error: src/foo.md:3: Missing");
+    }
+
+    #[test]
+    fn test_render_json_with_location() {
+        let diagnostic = Diagnostic::error("Missing".to_string()).at(PathBuf::from("src/foo.md"), 3);
+
+        assert_eq!(diagnostic.render(OutputFormat::Json), r#"{"file":"src/foo.md","level":"error","line":3,"message":"Missing"}"#);
+    }
+
+    #[test]
+    fn test_render_github_missing_reference() {
+        let diagnostic = Diagnostic::error("Found request to replace link with 'foo', but no chapter with that name found.".to_string())
+            .at(PathBuf::from("src/guide/intro.md"), 12);
+
+        assert_eq!(
+            diagnostic.render(OutputFormat::Github),
+            "::error file=src/guide/intro.md,line=12::Found request to replace link with 'foo', but no chapter with that name found."
+        );
+    }
+
+    #[test]
+    fn test_render_github_without_location() {
+        let diagnostic = Diagnostic::warning("Found duplicate chapter name foo".to_string());
+
+        assert_eq!(diagnostic.render(OutputFormat::Github), "::warning::Found duplicate chapter name foo");
+    }
+
+    #[test]
+    fn test_render_note() {
+        let diagnostic = Diagnostic::note("Took 3ms\n  slowest".to_string());
+
+        assert_eq!(diagnostic.render(OutputFormat::Plain), "Note: Took 3ms\n  slowest");
+        assert_eq!(diagnostic.render(OutputFormat::Json), r#"{"level":"note","message":"Took 3ms\n  slowest"}"#);
+        assert_eq!(diagnostic.render(OutputFormat::Github), "::notice::Took 3ms%0A  slowest");
+    }
+
+    #[test]
+    fn test_render_github_escapes() {
+        let diagnostic = Diagnostic::warning("100% broken\nsecond line".to_string()).at(PathBuf::from("src/a,b.md"), 1);
+
+        assert_eq!(diagnostic.render(OutputFormat::Github), "::warning file=src/a%2Cb.md,line=1::100%25 broken%0Asecond line");
+    }
+}
@@ -1,20 +1,230 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use regex::{Regex, Captures};
+use regex::Regex;
+use serde_json::json;
 
-use mdbook::book::{Book, BookItem};
+use chrono::{DateTime, Utc};
+use chrono::format::{Item, StrftimeItems};
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::Config;
 use mdbook::errors::Error;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::utils::{normalize_id, render_markdown};
 
-pub struct PathProcessor;
+mod diagnostics;
+
+use diagnostics::{Diagnostic, Level, OutputFormat};
+
+/// The `chapter-path` preprocessor. Create one with [`PathProcessor::new`].
+#[derive(Default)]
+pub struct PathProcessor {
+    // Custom directives, keyed by keyword.
+    handlers: HashMap<String, DirectiveHandler>
+}
+
+/// Expands a custom directive registered with [`PathProcessor::register_directive`].
+pub type DirectiveHandler = Box<dyn Fn(&ParsedDirective<'_>, &DirectiveContext<'_>) -> Result<String, ProcessorError>>;
+
+/// What a custom directive handler can look up about the book while a chapter is processed.
+pub struct DirectiveContext<'a> {
+    processor: &'a PathProcessor,
+    chapter_names: &'a ChapterMap,
+    options: &'a PathProcessorOptions,
+    current_path: Option<&'a Path>
+}
+
+// Built-in and custom keywords as a regex alternation.
+fn directive_keywords(custom_keywords: &[&str]) -> String {
+    let mut keywords: Vec<String> = BUILTIN_KEYWORDS.iter().map(|keyword| keyword.to_string()).collect();
+    keywords.extend(custom_keywords.iter().map(|keyword| regex::escape(keyword)));
+    keywords.join("|")
+}
+
+// The first directive whose argument has braces the parser can't balance, such as `{{#path_for a{b}}`.
+// These would otherwise be silently left unprocessed or cut short at the first `}}`. A candidate
+// ends at its first `}}`, or just before a line break or the next `{{#` if it's never closed.
+fn unbalanced_directive<'a>(content: &'a str, custom_keywords: &[&str], parsed: &[ParsedDirective]) -> Option<(usize, &'a str)> {
+    let argument = r"(?:[^{}\n]|\}[^{}\n]|\{[^{}\n]|\{\{[^#{}\n])*";
+    let lenient = Regex::new(&format!(r"(?P<escape>\\)?\{{\{{#(?:{})(?:}}}}| {}(?:}}}})?)", directive_keywords(custom_keywords), argument)).unwrap();
+
+    let unbalanced = lenient.captures_iter(content)
+        .filter(|capture| capture.name("escape").is_none())
+        .map(|capture| capture.get(0).unwrap())
+        .find(|found| !parsed.iter().any(|directive| directive.span.start == found.start()));
+    unbalanced.map(|found| (found.start(), found.as_str().trim_end()))
+}
+
+/// A `{{#path_for ...}}` directive found in chapter content, before any resolution.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParsedDirective<'a> {
+    /// Byte range of the whole directive, including a leading `\` if escaped.
+    pub span: Range<usize>,
+    /// The directive keyword: `path_for`, `short_path_for`, `backlinks_for`, `mtime_for`, a variable
+    /// such as `book_title`, or a custom one.
+    pub keyword: &'a str,
+    /// The chapter name (or `@` path hint); empty for the default target.
+    pub name: &'a str,
+    /// The part after the first `#`, if any. A further `#` in it is an error when the directive
+    /// is processed.
+    pub anchor: Option<&'a str>,
+    /// Whether the directive was escaped as `\{{#...}}` and should be left as literal text.
+    pub escaped: bool
+}
+
+/// Finds every directive in `content`, in order, without resolving any of them.
+pub fn parse_directives(content: &str) -> Vec<ParsedDirective<'_>> {
+    parse_directives_with(content, &[])
+}
+
+// Also finds directives with the given custom keywords.
+fn parse_directives_with<'a>(content: &'a str, custom_keywords: &[&str]) -> Vec<ParsedDirective<'a>> {
+    // The argument may contain one level of balanced braces, e.g. an anchor like `#fn-{}`.
+    let regex = Regex::new(&format!(r"(?P<escape>\\)?\{{\{{#(?P<keyword>{})(?: (?P<file>(?:[^{{}}\n]|\{{[^{{}}\n]*\}})+?))?}}}}", directive_keywords(custom_keywords))).unwrap();
+
+    regex.captures_iter(content).map(|capture| {
+        let full_match = capture.get(0).unwrap();
+        let file_link = FileLink::split(capture.name("file").map(|file| file.as_str()).unwrap_or_default());
+
+        ParsedDirective {
+            span: full_match.range(),
+            keyword: capture.name("keyword").unwrap().as_str(),
+            name: file_link.name,
+            anchor: file_link.anchor,
+            escaped: capture.name("escape").is_some()
+        }
+    }).collect()
+}
+
+/// A directive corrected by [`PathProcessor::fix_references`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReferenceFix {
+    /// 1-based line of the directive in its chapter file.
+    pub line: usize,
+    /// The chapter name as it was written.
+    pub from: String,
+    /// The existing chapter name it was corrected to.
+    pub to: String
+}
+
+/// A chapter file with corrected references, as returned by [`PathProcessor::fix_references`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FixedChapter {
+    /// The chapter file, relative to the book's source directory.
+    pub source_path: PathBuf,
+    /// The full chapter content with every fix applied.
+    pub content: String,
+    pub fixes: Vec<ReferenceFix>
+}
+
+// One chapter's references to another, as counted for backlinks and the reference graph.
+struct Reference {
+    source: ChapterEntry,
+    target: ChapterEntry,
+    count: usize
+}
+
+// A chapter that directives can link to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ChapterEntry {
+    // The chapter's name as written in SUMMARY.md.
+    name: String,
+    path: PathBuf,
+    // Used as-is instead of a URL built from `path`. Set for entries from a manifest.
+    url: Option<String>
+}
+
+// Heading and footnote ids in a chapter, each with how many elements share it before mdBook de-duplicates
+// them. Two `## Usage` headings get the ids `usage` (shared by 2) and `usage-1` (1).
+type HeadingIds = HashMap<String, usize>;
+
+// Known chapters, keyed by `chapter_key` of their names.
+type ChapterMap = HashMap<String, ChapterEntry>;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum ProcessorError {
     // Tried to provide path to the given chapter, but couldn't find one.
     ChapterNotFound(String),
     // Duplicate chapter names found. Only an issue when strict mode is on.
-    DuplicateChapterNames(String)
+    DuplicateChapterNames(String),
+    // A path hint matched several chapters and the resolution policy couldn't pick one.
+    AmbiguousPathHint(String, Vec<PathBuf>),
+    // A directive without a chapter name was used, but the default target chapter doesn't exist.
+    DefaultTargetNotFound(String),
+    // `{{#short_path_for}}` was used for the given chapter, but no `short-url-template` is configured.
+    ShortUrlTemplateMissing(String),
+    // A directive's argument has braces that aren't balanced. Holds the directive as written.
+    UnbalancedBraces(String),
+    // A path hint used backslashes as separators. Holds the hint as written and its corrected form.
+    BackslashInPathHint(String, String),
+    // The configured manifest couldn't be read or parsed.
+    InvalidManifest(PathBuf, String),
+    // With anchor validation on, the named chapter has no heading with the given anchor.
+    AnchorNotFound(String, String),
+    // The anchor names several headings in the named chapter. Holds the name, anchor and heading count.
+    AmbiguousAnchor(String, String, usize),
+    // The link to the named chapter would lead above the site root. Holds the name and the URL.
+    EscapesRoot(String, String),
+    // A directive that expands to several items found none, and `empty-result` is `error`. Holds the
+    // directive keyword and the chapter name.
+    EmptyResult(String, String),
+    // A reference with more than one `#`. Holds the reference as written.
+    MultipleAnchors(String),
+    // A custom directive was registered under a built-in keyword. Holds the keyword.
+    BuiltinKeyword(String),
+    // A custom directive keyword that isn't made of letters, digits and `_`. Holds the keyword.
+    InvalidKeyword(String)
+}
+
+impl fmt::Display for ProcessorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessorError::ChapterNotFound(name) => write!(f, "Found request to replace link with '{}', but no chapter with that name found.", name),
+            ProcessorError::DuplicateChapterNames(name) => write!(f, "Found duplicate chapter name {} (duplicates are not allowed in strict mode)", name),
+            ProcessorError::AmbiguousPathHint(hint, paths) => {
+                let paths: Vec<&str> = paths.iter().map(|path| path.to_str().unwrap()).collect();
+                write!(f, "Path hint '@{}' matches multiple chapters: {}", hint, paths.join(", "))
+            },
+            ProcessorError::DefaultTargetNotFound(name) => write!(f, "Found request to link to the default target, but the default target chapter '{}' doesn't exist.", name),
+            ProcessorError::ShortUrlTemplateMissing(name) => write!(f, "Found request for a short link to '{}', but short-url-template isn't configured.", name),
+            ProcessorError::UnbalancedBraces(directive) => write!(f, "Directive '{}' has unbalanced braces; arguments may only contain matched pairs like '{{}}'.", directive),
+            ProcessorError::BackslashInPathHint(hint, corrected) => write!(f, "Path hint '{}' uses backslashes as separators; use '{}' instead.", hint, corrected),
+            ProcessorError::InvalidManifest(path, reason) => write!(f, "Unable to load manifest {}: {}", path.display(), reason),
+            ProcessorError::AnchorNotFound(name, anchor) => write!(f, "Found request to link to '{}#{}', but that chapter has no heading with that id.", name, anchor),
+            ProcessorError::AmbiguousAnchor(name, anchor, count) => write!(f, "Anchor '{}#{}' matches {} headings with the same id.", name, anchor, count),
+            ProcessorError::EscapesRoot(name, url) => write!(f, "Link to '{}' resolves to '{}', which leads outside the site root.", name, url),
+            ProcessorError::EmptyResult(keyword, name) => write!(f, "Found request for {} '{}', but there is nothing to list.", keyword, name),
+            ProcessorError::MultipleAnchors(reference) => write!(f, "Reference '{}' has more than one '#'; only a single anchor is allowed.", reference),
+            ProcessorError::BuiltinKeyword(keyword) => write!(f, "Can't register a custom '{}' directive; it's a built-in keyword.", keyword),
+            ProcessorError::InvalidKeyword(keyword) => write!(f, "Can't register a custom '{}' directive; keywords may only contain letters, digits and '_'.", keyword)
+        }
+    }
+}
+
+impl std::error::Error for ProcessorError {}
+
+// A directive that couldn't be resolved, and where in the chapter it was.
+struct DirectiveError {
+    error: ProcessorError,
+    // Byte offset of the directive within the chapter content.
+    offset: usize
+}
+
+// Something worth pointing out about a directive that still resolved.
+struct DirectiveWarning {
+    message: String,
+    // Byte offset of the directive within the chapter content.
+    offset: usize,
+    // The missing chapter, when the warning stands in for a `ChapterNotFound` error.
+    missing: Option<String>
 }
 
 struct FileLink<'a> {
@@ -22,175 +232,4266 @@ struct FileLink<'a> {
     anchor: Option<&'a str>
 }
 
+// Link syntaxes of other tools, recognized to ease migrating a book onto this preprocessor.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CompatSyntax {
+    // Zola's internal links: `[text](@/guide/setup.md#anchor)`, rooted at the content directory.
+    Zola
+}
+
+// Where a reference is looked up, tried in the order given by `resolution-order`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Lookup {
+    // Names from the manifest.
+    Manifest,
+    // The book's chapter names.
+    Name,
+    // Chapter paths, as if the reference were an `@` path hint.
+    Path
+}
+
+// How much of an emitted chapter path and anchor is percent-encoded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum UrlEncoding {
+    // Everything RFC 3986 doesn't allow in a path or fragment.
+    Strict,
+    // Only characters that break markdown links or HTML attributes, and `#` and `?` in paths.
+    Lenient,
+    // Emitted as written.
+    Off
+}
+
+// How strictly an optional check is enforced.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CheckLevel {
+    // Don't check.
+    Off,
+    // Report a warning and carry on.
+    Warn,
+    // Fail the build.
+    Error
+}
+
+// How to pick a chapter when a path hint (`{{#path_for @setup}}`) matches more than one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PathHintResolution {
+    // More than one match is an error.
+    Unique,
+    // Pick the match with the most path components.
+    Deepest,
+    // Pick the match with the fewest path components.
+    Shallowest
+}
+
+// What to do about a reference to a chapter that doesn't exist.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum MissingChapter {
+    // Fail the build.
+    Error,
+    // Leave the directive as written and warn once per missing chapter.
+    Warn
+}
+
+// How directives that expand to several links, such as `{{#backlinks_for}}`, lay them out.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ListStyle {
+    // One `- ` item per line.
+    Tight,
+    // One `- ` item per line, with blank lines between items.
+    Loose,
+    // Comma-separated on a single line, for use inside a sentence.
+    Inline
+}
+
+// The rules mdBook's HTML renderer has used to turn heading text into ids, so anchors can be
+// validated against the mdBook version that renders the book.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum IdAlgorithm {
+    // mdBook 0.4.8, which this preprocessor is built against: only `<em>`, `<code>` and `<strong>`
+    // tags are removed, so the text of other tags, such as links, ends up in the id.
+    V04,
+    // Later mdBook releases: every HTML tag is removed.
+    Latest
+}
+
+// What a directive that expands to several items, such as `{{#backlinks_for}}`, becomes when there are none.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum EmptyResult {
+    Error,
+    Empty,
+    // This text instead of the list.
+    Placeholder(String)
+}
+
+// The `[preprocessor.chapter-path]` table. Every getter checks the value's type, and collects a
+// warning when it's wrong or not one of the allowed choices. Keys that aren't set give nothing.
+struct ConfigTable<'a> {
+    table: Option<&'a toml::value::Table>,
+    warnings: Vec<String>
+}
+
 struct PathProcessorOptions {
     site_path: String,
-    strict_mode: bool
+    // Link relative to the referencing chapter instead of from `site_path`.
+    relative_links: bool,
+    strict_mode: bool,
+    skip_marker: String,
+    output_format: OutputFormat,
+    // Characters stripped from chapter names and references before matching.
+    ignore_chars: Vec<char>,
+    path_hint_resolution: PathHintResolution,
+    // Values for `{{#book_title}}` and `{{#chapter_count}}`, keyed by variable name.
+    variables: HashMap<&'static str, String>,
+    // When set, time each chapter and print the slowest ones at the end of the run.
+    profile: bool,
+    // Template for the error reported when a chapter can't be found.
+    missing_message: String,
+    // The book's source directory, used to point diagnostics at chapter files.
+    src_dir: PathBuf,
+    // The book's root directory, for reading chapter files. Empty unless known.
+    book_root: PathBuf,
+    mtime_format: String,
+    // Chapter used by `{{#path_for}}` without a name. Defaults to the book's first chapter.
+    default_target: Option<String>,
+    // A name, like `home`, that also refers to the default target.
+    default_target_keyword: Option<String>,
+    // Warn when a reference's casing differs from the chapter name it matched.
+    warn_case_mismatch: bool,
+    // Percent-encode `|` in emitted URLs so they can't split markdown table cells.
+    table_safe: bool,
+    encoding: UrlEncoding,
+    // Prepended to emitted anchors, for themes that prefix heading ids.
+    anchor_prefix: String,
+    // Pattern for `{{#short_path_for}}` URLs, e.g. `/s/{slug}`.
+    short_url_template: Option<String>,
+    // Whether directives in part titles are processed, rather than only warned about.
+    process_part_titles: bool,
+    // Where diagnostics are also written, truncated at the start of each run.
+    diagnostics_file: Option<PathBuf>,
+    // Another tool's link syntax to recognize alongside our directives.
+    compat: Option<CompatSyntax>,
+    // Path hints written with backslashes, usually Windows paths pasted in. When warning, the hint
+    // is resolved with forward slashes instead.
+    backslash_check: CheckLevel,
+    // Links that would lead above the site root.
+    escape_check: CheckLevel,
+    // JSON or TOML file of extra chapter names and their URLs, which take precedence over the book's.
+    manifest: Option<PathBuf>,
+    // Chapters referencing each chapter, keyed by `chapter_key` of the referenced chapter's name.
+    // Collected from the whole book before any chapter is processed.
+    backlinks: HashMap<String, Vec<ChapterEntry>>,
+    // Ordering prefix (e.g. the `01-` in `01-intro.md`) that path hints may leave out.
+    number_prefix: Option<Regex>,
+    list_style: ListStyle,
+    empty_result: EmptyResult,
+    // Check that anchors in references name a heading in the target chapter.
+    validate_anchors: bool,
+    id_algorithm: IdAlgorithm,
+    // Heading ids of each chapter, keyed by chapter path. Collected when `validate_anchors` is on.
+    anchors: HashMap<PathBuf, HeadingIds>,
+    // Anchors shared by several headings in the target chapter. Off links to the first without comment.
+    ambiguous_anchor: CheckLevel,
+    missing_chapter: MissingChapter,
+    // Resolve and report everything, but hand back the book unchanged and don't fail the build.
+    report_only: bool,
+    // Where the graph of references between chapters is written, if anywhere.
+    graph_file: Option<PathBuf>,
+    // Unknown or invalid config values, which fall back to defaults.
+    config_warnings: Vec<String>,
+    // Report errors in draft chapters as warnings, leaving the chapter as it is.
+    lenient_in_drafts: bool,
+    // Chapter paths that count as drafts besides mdBook's own drafts, which have no file.
+    draft_pattern: Option<Regex>,
+    resolution_order: Vec<Lookup>
 }
 
-impl FileLink<'_> {
-    fn from_string(string: &str) -> FileLink {
-        let splitted: Vec<&str> = string.split("#").collect();
+// Placeholders: {name} is the requested chapter, {file} the chapter containing the reference,
+// and {suggestion} the closest known chapter name (empty if nothing is close).
+const DEFAULT_MISSING_MESSAGE: &str = "Found request to replace link with '{name}', but no chapter with that name found.";
+
+// Extensions a path hint may carry, which are ignored when matching.
+const PATH_HINT_EXTENSIONS: [&str; 2] = [".md", ".html"];
+
+// Preprocessors that undo our work if they run after us: `links` pulls in `{{#include}}`d text,
+// whose directives we would never see.
+const CONFLICTING_PREPROCESSORS: [&str; 1] = ["links"];
+
+// What mdbook runs before any configured preprocessor unless `use-default-preprocessors = false`.
+const DEFAULT_PREPROCESSORS: [&str; 2] = ["links", "index"];
+
+// How many chapters the profile summary lists.
+const PROFILE_SUMMARY_LENGTH: usize = 10;
 
-        if splitted.len() > 2 {
-            panic!("Invalid link parsed: Multiple '#'s detected for {}", string);
+// Numbering stripped from path components when `strip-number-prefix = true`.
+const DEFAULT_NUMBER_PREFIX: &str = r"^\d+[-_]";
+
+const BUILTIN_KEYWORDS: [&str; 6] = ["path_for", "short_path_for", "backlinks_for", "mtime_for", "book_title", "chapter_count"];
+
+// Keywords replaced with a book-wide value from `variables`. They take no argument.
+const VARIABLE_KEYWORDS: [&str; 2] = ["book_title", "chapter_count"];
+
+// strftime-style format for `{{#mtime_for}}`.
+const DEFAULT_MTIME_FORMAT: &str = "%Y-%m-%d";
+
+// Manifest entries override chapters of the same name, and references aren't matched against paths
+// unless they're path hints.
+const DEFAULT_RESOLUTION_ORDER: [Lookup; 2] = [Lookup::Manifest, Lookup::Name];
+
+// Chapters in a `drafts` directory, at any depth, count as drafts with `lenient-in-drafts = true`.
+const DEFAULT_DRAFT_PATTERN: &str = r"(^|/)drafts/";
+
+const DISABLE_ENV_VAR: &str = "MDBOOK_CHAPTER_PATH_DISABLE";
+
+const DEFAULT_SKIP_MARKER: &str = "<!-- chapter-path: skip -->";
+
+impl DirectiveContext<'_> {
+    /// The URL `{{#path_for reference}}` would emit, e.g. for `Getting Started#install`.
+    pub fn chapter_url(&self, reference: &str) -> Result<String, ProcessorError> {
+        let file_link = FileLink::from_string(reference)?;
+        let entry = self.processor.resolve(&file_link, self.chapter_names, self.options)?;
+        Ok(self.processor.entry_url(entry, file_link.anchor, self.current_path, self.options))
+    }
+
+    /// The name of the chapter `reference` resolves to, as written in SUMMARY.md.
+    pub fn chapter_name(&self, reference: &str) -> Result<String, ProcessorError> {
+        let file_link = FileLink::from_string(reference)?;
+        Ok(self.processor.resolve(&file_link, self.chapter_names, self.options)?.name.clone())
+    }
+
+    /// The path of the chapter being processed, relative to the source directory, if it has one.
+    pub fn current_path(&self) -> Option<&Path> {
+        self.current_path
+    }
+}
+
+impl<'a> ConfigTable<'a> {
+    fn value(&self, key: &str) -> Option<&'a toml::value::Value> {
+        self.table.and_then(|table| table.get(key))
+    }
+
+    fn invalid(&mut self, key: &str, value: &toml::value::Value, expected: &str) {
+        self.warnings.push(format!("Invalid {} value {}, expected {}; ignoring it", key, value, expected));
+    }
+
+    fn get_str(&mut self, key: &str) -> Option<&'a str> {
+        match self.value(key)? {
+            toml::value::Value::String(value) => Some(value),
+            value => {
+                self.invalid(key, value, "a string");
+                None
+            }
+        }
+    }
+
+    fn get_bool(&mut self, key: &str) -> Option<bool> {
+        match self.value(key)? {
+            toml::value::Value::Boolean(value) => Some(*value),
+            value => {
+                self.invalid(key, value, "true or false");
+                None
+            }
         }
-        let name = splitted[0];
-        let mut anchor: Option<&str> = None;
-        if splitted.len() == 2 {
-            anchor = Some(splitted[1]);
+    }
+
+    // The choice named by the value, or `default` if it's not set or names none of them.
+    fn get_enum<T: Clone + PartialEq>(&mut self, key: &str, choices: &[(&str, T)], default: T) -> T {
+        let value = match self.get_str(key) {
+            Some(value) => value,
+            None => return default
+        };
+        if let Some((_, choice)) = choices.iter().find(|(name, _)| *name == value) {
+            return choice.clone();
+        }
+
+        let fallback = match choices.iter().find(|(_, choice)| *choice == default) {
+            Some((name, _)) => format!("falling back to {}", name),
+            None => "ignoring it".to_string()
+        };
+        self.warnings.push(format!("Unknown {} '{}', {}", key, value, fallback));
+        default
+    }
+
+    // A regex given as a string, or `default_pattern` if the value is `true`.
+    fn get_pattern(&mut self, key: &str, default_pattern: &str) -> Option<Regex> {
+        let pattern = match self.value(key)? {
+            toml::value::Value::Boolean(true) => default_pattern,
+            toml::value::Value::Boolean(false) => return None,
+            toml::value::Value::String(value) => value.as_str(),
+            value => {
+                self.invalid(key, value, "true, false or a pattern");
+                return None;
+            }
+        };
+        match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(error) => {
+                self.warnings.push(format!("Invalid {} pattern '{}', ignoring it: {}", key, pattern, error));
+                None
+            }
         }
+    }
+}
+
+impl Lookup {
+    // The `resolution-order` entries, and the lookup each one names.
+    const CHOICES: [(&'static str, Lookup); 3] = [("manifest", Lookup::Manifest), ("name", Lookup::Name), ("path", Lookup::Path)];
+}
 
-        FileLink { name, anchor }
+impl IdAlgorithm {
+    // The id for a heading, given its rendered HTML contents.
+    fn heading_id(self, content: &str) -> String {
+        let mut content = content.to_string();
+        match self {
+            IdAlgorithm::V04 => {
+                for tag in &["<em>", "</em>", "<code>", "</code>", "<strong>", "</strong>"] {
+                    content = content.replace(tag, "");
+                }
+            },
+            IdAlgorithm::Latest => content = Regex::new(r"<.*?>").unwrap().replace_all(&content, "").into_owned()
+        }
+        for entity in &["&lt;", "&gt;", "&amp;", "&#39;", "&quot;"] {
+            content = content.replace(entity, "");
+        }
+        normalize_id(content.trim().trim_start_matches('#').trim())
     }
 }
 
-impl Preprocessor for PathProcessor {
-    fn name(&self) -> &str { "chapter-path" }
+impl UrlEncoding {
+    fn path(self, path: &str) -> String {
+        match self {
+            UrlEncoding::Strict => percent_encode(path, |c| is_pchar(c) || c == '/'),
+            UrlEncoding::Lenient => percent_encode(path, |c| !breaks_link(c) && c != '#' && c != '?'),
+            UrlEncoding::Off => path.to_string()
+        }
+    }
 
-    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        let options = self.process_options(ctx);
+    fn fragment(self, fragment: &str) -> String {
+        match self {
+            UrlEncoding::Strict => percent_encode(fragment, |c| is_pchar(c) || c == '/' || c == '?'),
+            UrlEncoding::Lenient => percent_encode(fragment, |c| !breaks_link(c)),
+            UrlEncoding::Off => fragment.to_string()
+        }
+    }
+}
 
-        let known_chapters = self.chapter_names(&book, &options).unwrap();
+impl CompatSyntax {
+    // The links in `content` written in this syntax, as the equivalent `path_for` directives.
+    fn directives(self, content: &str) -> Vec<ParsedDirective<'_>> {
+        match self {
+            CompatSyntax::Zola => {
+                // Only the `@/...` link target is replaced; the surrounding markdown is kept.
+                // A target with a second `#` isn't a valid link, so it isn't matched and stays as written.
+                let regex = Regex::new(r"\]\((?P<target>@/[^)#\s]+(?:#[^)#\s]+)?)\)").unwrap();
 
-        book.for_each_mut(|item| {
-            if let BookItem::Chapter(chapter) = item {
-                chapter.content = self.process_chapter(&chapter.content, &known_chapters, &options).unwrap();
+                regex.captures_iter(content).map(|capture| {
+                    let target = capture.name("target").unwrap();
+                    let file_link = FileLink::split(target.as_str());
+
+                    ParsedDirective {
+                        span: target.range(),
+                        keyword: "path_for",
+                        name: file_link.name,
+                        anchor: file_link.anchor,
+                        escaped: false
+                    }
+                }).collect()
             }
-        });
-        Ok(book)
+        }
+    }
+}
+
+impl FileLink<'_> {
+    fn from_string(string: &str) -> Result<FileLink<'_>, ProcessorError> {
+        let file_link = FileLink::split(string);
+        if matches!(file_link.anchor, Some(anchor) if anchor.contains('#')) {
+            return Err(ProcessorError::MultipleAnchors(string.to_string()));
+        }
+        Ok(file_link)
+    }
+
+    // Splits at the first `#`, leaving any further ones in the anchor for `from_string` to reject.
+    // The parser uses this so that a malformed directive is reported when it's processed.
+    fn split(string: &str) -> FileLink<'_> {
+        match string.find('#') {
+            Some(index) => FileLink { name: &string[..index], anchor: Some(&string[index + 1..]) },
+            None => FileLink { name: string, anchor: None }
+        }
+    }
+}
+
+impl Preprocessor for PathProcessor {
+    fn name(&self) -> &str { "chapter-path" }
+
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, Error> {
+        self.process_book(&ctx.config, &ctx.root, book)
     }
 
     fn supports_renderer(&self, renderer: &str) -> bool { renderer == "html" }
 }
 
 impl PathProcessor {
-    fn process_options(&self, ctx: &PreprocessorContext) -> PathProcessorOptions {
-        // process site_path
-        let mut site_path: String = "/".to_string();
-        if let Some(config) = ctx.config.get("output.html") {
-            if let Some(toml::value::Value::String(value)) = config.get("site-url") {
-                site_path = value.to_string();
+    // The body of `run`, apart from the context it's given.
+    fn process_book(&self, config: &Config, root: &Path, book: Book) -> Result<Book, Error> {
+        self.process_book_unless_disabled(config, root, book, env::var(DISABLE_ENV_VAR).ok().as_deref())
+    }
+
+    // `disable` is the value of `MDBOOK_CHAPTER_PATH_DISABLE`, if it's set.
+    fn process_book_unless_disabled(&self, config: &Config, root: &Path, mut book: Book, disable: Option<&str>) -> Result<Book, Error> {
+        let mut options = self.options_for_root(config, root);
+        if self.disabled(disable) {
+            // Nothing is processed, so the previous run's diagnostics file is left as it was.
+            options.diagnostics_file = None;
+            self.report(Diagnostic::warning(format!("{} is set, leaving the book unchanged", DISABLE_ENV_VAR)), &options);
+            return Ok(book);
+        }
+        self.start_diagnostics_file(&options);
+        self.report_config_warnings(&options);
+        for warning in self.ordering_warnings(config) {
+            self.report(Diagnostic::warning(warning), &options);
+        }
+        let chapter_count = book.iter().filter(|item| matches!(item, BookItem::Chapter(chapter) if !chapter.is_draft_chapter())).count();
+        options.variables.insert("chapter_count", chapter_count.to_string());
+        if options.default_target.is_none() {
+            options.default_target = book.iter().find_map(|item| match item {
+                BookItem::Chapter(chapter) if !chapter.is_draft_chapter() => Some(chapter.name.clone()),
+                _ => None
+            });
+        }
+
+        let original_book = if options.report_only { Some(book.clone()) } else { None };
+
+        let known_chapters = match self.chapter_names(&book, &options) {
+            Ok(chapters) => chapters,
+            Err(error) if options.report_only => {
+                self.report(Diagnostic::error(error.to_string()), &options);
+                return Ok(book);
+            },
+            Err(error) => return Err(self.fail(Diagnostic::error(error.to_string()), &options))
+        };
+        let references = self.collect_references(&book, &known_chapters, &options);
+        options.backlinks = self.backlinks(&references, &options);
+        if let Some(file) = &options.graph_file {
+            if let Err(error) = fs::write(file, self.reference_graph(&book, &references, file)) {
+                self.report(Diagnostic::warning(format!("Unable to write reference graph to {}: {}", file.display(), error)), &options);
             }
         }
+        if options.validate_anchors {
+            options.anchors = self.collect_anchors(&book, &options);
+        }
 
-        if site_path.ends_with("/") == false {
-            site_path.push_str("/");
+        let mut result: Result<(), (DirectiveError, Option<PathBuf>, String)> = Ok(());
+        let mut timings: Vec<(String, Duration)> = Vec::new();
+        // Missing chapters in warn mode, each with the first reference to it and how many there were.
+        let mut missing_references: Vec<(String, Diagnostic, usize)> = Vec::new();
+        book.for_each_mut(|item| {
+            if result.is_err() {
+                return;
+            }
+            if let BookItem::Chapter(chapter) = item {
+                let start = Instant::now();
+                let lenient = options.lenient_in_drafts && self.is_draft(chapter, &options);
+                let mut warnings: Vec<DirectiveWarning> = Vec::new();
+                match self.process_chapter_located(&chapter.content, chapter.path.as_deref(), &known_chapters, &options, &mut warnings) {
+                    Ok(content) => {
+                        for warning in warnings {
+                            if let Some(missing) = warning.missing {
+                                if let Some(existing) = missing_references.iter_mut().find(|(name, _, _)| *name == missing) {
+                                    existing.2 += 1;
+                                    continue;
+                                }
+                                let message = self.missing_message(&missing, chapter.source_path.as_deref(), &known_chapters, &options);
+                                let diagnostic = self.locate(Diagnostic::warning(message), chapter.source_path.as_deref(), &chapter.content, warning.offset, &options);
+                                missing_references.push((missing, diagnostic, 1));
+                                continue;
+                            }
+                            let diagnostic = Diagnostic::warning(warning.message);
+                            self.report(self.locate(diagnostic, chapter.source_path.as_deref(), &chapter.content, warning.offset, &options), &options);
+                        }
+                        chapter.content = content;
+                    },
+                    Err(error) if options.report_only => {
+                        let diagnostic = self.directive_error(error, chapter.source_path.as_deref(), &chapter.content, &known_chapters, &options);
+                        self.report(diagnostic, &options);
+                    },
+                    Err(error) if lenient => {
+                        let mut diagnostic = self.directive_error(error, chapter.source_path.as_deref(), &chapter.content, &known_chapters, &options);
+                        diagnostic.level = Level::Warning;
+                        self.report(diagnostic, &options);
+                    },
+                    Err(error) => result = Err((error, chapter.source_path.clone(), chapter.content.clone()))
+                }
+                if options.profile {
+                    timings.push((chapter.name.clone(), start.elapsed()));
+                }
+            }
+            if let BookItem::PartTitle(title) = item {
+                let mut warnings: Vec<String> = Vec::new();
+                match self.process_part_title(title, &known_chapters, &options, &mut warnings) {
+                    Ok(Some(processed)) => *title = processed,
+                    Ok(None) => {},
+                    Err(error) if options.report_only => {
+                        let diagnostic = self.directive_error(error, None, title, &known_chapters, &options);
+                        self.report(diagnostic, &options);
+                    },
+                    Err(error) => result = Err((error, None, title.clone()))
+                }
+                for warning in warnings {
+                    self.report(Diagnostic::warning(warning), &options);
+                }
+            }
+        });
+
+        for (_, mut diagnostic, count) in missing_references {
+            if count > 1 {
+                diagnostic.message.push_str(&format!(" ({} references)", count));
+            }
+            self.report(diagnostic, &options);
+        }
+
+        if options.profile {
+            let summary = self.profile_summary(timings, PROFILE_SUMMARY_LENGTH);
+            self.report(Diagnostic::note(summary.trim_end().to_string()), &options);
         }
 
-        let mut strict_mode = false;
-        if let Some(config) = ctx.config.get_preprocessor("chapter-path") {
-            if let Some(toml::value::Value::Boolean(value)) = config.get("strict") {
-                strict_mode = *value;
+        match result {
+            Ok(()) => Ok(original_book.unwrap_or(book)),
+            Err((error, file, content)) => {
+                let diagnostic = self.directive_error(error, file.as_deref(), &content, &known_chapters, &options);
+                Err(self.fail(diagnostic, &options))
             }
         }
+    }
 
-        PathProcessorOptions {
-            site_path,
-            strict_mode
+    fn is_draft(&self, chapter: &Chapter, options: &PathProcessorOptions) -> bool {
+        match (&chapter.path, &options.draft_pattern) {
+            (None, _) => true,
+            (Some(path), Some(pattern)) => pattern.is_match(&path.to_string_lossy()),
+            (Some(_), None) => false
         }
     }
 
-    fn chapter_names(&self, book: &Book, options: &PathProcessorOptions) -> Result<HashMap<String, PathBuf>, ProcessorError>{
-        let mut mapping: HashMap<String, PathBuf> = HashMap::new();
+    fn directive_error(&self, error: DirectiveError, file: Option<&Path>, content: &str, chapter_names: &ChapterMap, options: &PathProcessorOptions) -> Diagnostic {
+        let DirectiveError { error, offset } = error;
+        let message = match &error {
+            ProcessorError::ChapterNotFound(name) => self.missing_message(name, file, chapter_names, options),
+            _ => error.to_string()
+        };
+        self.locate(Diagnostic::error(message), file, content, offset, options)
+    }
 
-        for item in book.iter() {
-            if let BookItem::Chapter(chapter) = item {
-                if let Option::Some(path) = &chapter.path {
-                    if let Some(existing_path) = mapping.get(&chapter.name.to_lowercase()) {
-                        if options.strict_mode {
-                            return Err(ProcessorError::DuplicateChapterNames(chapter.name.to_lowercase()));
-                        } else {
-                            eprintln!("Warning: Found duplicate chapter name {} at {} (existing chapter at {})", chapter.name, path.to_str().unwrap(), existing_path.to_str().unwrap());
-                        }
-                    }
-                    mapping.insert(chapter.name.to_lowercase(), path.to_path_buf());
-                }
+    // Set to anything but `0` or `false` to skip processing, e.g. while debugging a build.
+    // `value` is the variable's value, if it's set.
+    fn disabled(&self, value: Option<&str>) -> bool {
+        match value {
+            Some(value) => !value.is_empty() && value != "0" && value != "false",
+            None => false
+        }
+    }
+
+    /// Creates a preprocessor with no custom directives.
+    pub fn new() -> PathProcessor {
+        PathProcessor::default()
+    }
+
+    /// Adds a `{{#keyword ...}}` directive, expanded by `handler`.
+    ///
+    /// The handler gets the parsed directive and a [`DirectiveContext`] for looking up chapters,
+    /// and returns the text that replaces the directive. Errors fail the build like a missing
+    /// chapter does. The handler runs for every occurrence of the directive; its output isn't cached.
+    ///
+    /// Keywords may only contain ASCII letters, digits and `_`; anything else returns
+    /// [`ProcessorError::InvalidKeyword`]. Built-in keywords such as `path_for` can't be replaced;
+    /// registering one returns [`ProcessorError::BuiltinKeyword`].
+    pub fn register_directive<F>(&mut self, keyword: &str, handler: F) -> Result<(), ProcessorError>
+        where F: Fn(&ParsedDirective<'_>, &DirectiveContext<'_>) -> Result<String, ProcessorError> + 'static {
+        if keyword.is_empty() || !keyword.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(ProcessorError::InvalidKeyword(keyword.to_string()));
+        }
+        if BUILTIN_KEYWORDS.contains(&keyword) {
+            return Err(ProcessorError::BuiltinKeyword(keyword.to_string()));
+        }
+        self.handlers.insert(keyword.to_string(), Box::new(handler));
+        Ok(())
+    }
+
+    /// Returns the name and URL of every chapter in the book, in book order.
+    ///
+    /// URLs are built the same way as `{{#path_for}}` replacements, so this can be used to
+    /// generate sitemaps or feeds that agree with the links in the rendered book.
+    pub fn chapter_urls(&self, ctx: &PreprocessorContext, book: &Book) -> Vec<(String, String)> {
+        let options = self.process_options(ctx);
+        self.report_config_warnings(&options);
+        self.urls_for_book(book, &options)
+    }
+
+    /// Reports a warning noticed outside the preprocessor, e.g. by the command-line tool, the way the
+    /// preprocessor's own diagnostics are: in the configured `output-format`, and to `diagnostics-file`.
+    pub fn report_warning(&self, ctx: &PreprocessorContext, message: &str) {
+        self.report(Diagnostic::warning(message.to_string()), &self.process_options(ctx));
+    }
+
+    /// Like [`PathProcessor::report_warning`], for errors.
+    pub fn report_error(&self, ctx: &PreprocessorContext, message: &str) {
+        self.report(Diagnostic::error(message.to_string()), &self.process_options(ctx));
+    }
+
+    fn urls_for_book(&self, book: &Book, options: &PathProcessorOptions) -> Vec<(String, String)> {
+        book.iter().filter_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter.path.as_ref()
+                .and_then(|path| self.normalize_chapter_path(path).ok())
+                .map(|path| (chapter.name.clone(), self.chapter_url(&path, None, None, options))),
+            _ => None
+        }).collect()
+    }
+
+    // The URL `{{#path_for}}` emits for an entry.
+    fn entry_url(&self, entry: &ChapterEntry, anchor: Option<&str>, from: Option<&Path>, options: &PathProcessorOptions) -> String {
+        match &entry.url {
+            Some(url) => self.manifest_url(url, anchor, options),
+            None => self.chapter_url(&entry.path, anchor, from, options)
+        }
+    }
+
+    // Manifest URLs may point outside the book, so only the anchor is appended.
+    fn manifest_url(&self, url: &str, anchor: Option<&str>, options: &PathProcessorOptions) -> String {
+        let mut url = url.to_string();
+        if let Some(anchor) = anchor {
+            url.push('#');
+            url.push_str(anchor);
+        }
+        if options.table_safe {
+            url = url.replace('|', "%7C");
+        }
+        url
+    }
+
+    // `{{#short_path_for}}` URLs come from `short-url-template`, with `{slug}` derived from the chapter name.
+    fn short_url(&self, entry: &ChapterEntry, anchor: Option<&str>, options: &PathProcessorOptions) -> Result<String, ProcessorError> {
+        let template = options.short_url_template.as_ref()
+            .ok_or_else(|| ProcessorError::ShortUrlTemplateMissing(entry.name.clone()))?;
+
+        let mut url = template.replace("{slug}", &options.encoding.path(&normalize_id(&entry.name)));
+        self.push_anchor(&mut url, anchor, options);
+        if options.table_safe {
+            url = url.replace('|', "%7C");
+        }
+        Ok(url)
+    }
+
+    // `from` is the path of the chapter containing the link, needed for relative links.
+    fn chapter_url(&self, path: &Path, anchor: Option<&str>, from: Option<&Path>, options: &PathProcessorOptions) -> String {
+        let mut url = match from {
+            Some(from) if options.relative_links => {
+                let relative = relative_path(from.parent().unwrap_or_else(|| Path::new("")), path);
+                options.encoding.path(&relative.to_str().unwrap().replace('\\', "/"))
+            },
+            _ => {
+                let mut url = options.site_path.clone();
+                url.push_str(&options.encoding.path(path.to_str().unwrap()));
+                url
             }
         };
-        Ok(mapping)
+        self.push_anchor(&mut url, anchor, options);
+        if options.table_safe {
+            url = url.replace('|', "%7C");
+        }
+        url
+    }
+
+    // Appends `#anchor` to a URL within the book, with `anchor-prefix` and `encoding` applied.
+    fn push_anchor(&self, url: &mut String, anchor: Option<&str>, options: &PathProcessorOptions) {
+        if let Some(anchor) = anchor {
+            url.push('#');
+            if !is_line_anchor(anchor) {
+                url.push_str(&options.encoding.fragment(&options.anchor_prefix));
+            }
+            url.push_str(&options.encoding.fragment(anchor));
+        }
     }
 
-    fn process_chapter(&self, content: &str, chapter_names: &HashMap<String, PathBuf>, options: &PathProcessorOptions) -> Result<String, ProcessorError> {
-        let regex = Regex::new(r"\{\{#path_for (?P<file>.+?)}}").unwrap();
+    fn process_options(&self, ctx: &PreprocessorContext) -> PathProcessorOptions {
+        self.options_for_root(&ctx.config, &ctx.root)
+    }
 
-        let captures: Vec<Captures> = regex.captures_iter(&content).collect();
+    // Options with configured file paths resolved against the book's root directory.
+    fn options_for_root(&self, book_config: &Config, root: &Path) -> PathProcessorOptions {
+        let mut options = self.options_from_config(book_config);
+        options.diagnostics_file = options.diagnostics_file.map(|file| root.join(file));
+        options.manifest = options.manifest.map(|file| root.join(file));
+        options.graph_file = options.graph_file.map(|file| root.join(file));
+        options.book_root = root.to_path_buf();
+        options
+    }
 
-        let mut processed_content = String::new();
+    fn options_from_config(&self, book_config: &Config) -> PathProcessorOptions {
+        // Problems with the config are collected here, and reported along with everything else once
+        // the output format is known.
+        let mut config = ConfigTable { table: book_config.get_preprocessor("chapter-path"), warnings: Vec::new() };
 
-        let mut last_endpoint: usize = 0;
+        // process site_path
+        let mut site_url: Option<String> = None;
+        if let Some(config) = book_config.get("output.html") {
+            if let Some(toml::value::Value::String(value)) = config.get("site-url") {
+                site_url = Some(value.to_string());
+            }
+        }
+
+        // Without a site-url, links are root-relative unless configured otherwise.
+        let default_site_path = config.get_str("default-site-path");
+        let mut relative_links = config.get_enum("default-mode", &[("root", false), ("relative", true)], false);
+
+        let mut site_path: String = "/".to_string();
+        if let Some(site_url) = site_url {
+            site_path = site_url;
+            relative_links = false;
+        } else if let Some(default_site_path) = default_site_path {
+            site_path = default_site_path.to_string();
+            relative_links = false;
+        }
+
+        if site_path.ends_with("/") == false {
+            site_path.push_str("/");
+        }
 
-        for capture in captures {
-            let full_match = capture.get(0).unwrap();
+        let strict_mode = config.get_bool("strict").unwrap_or(false);
 
-            if let Some(file_name) = capture.name("file") {
-                let file_link = FileLink::from_string(file_name.as_str());
-                if let Some(path) = chapter_names.get(&file_link.name.to_lowercase()) {
-                    processed_content.push_str(&content[last_endpoint..full_match.start()]);
-                    last_endpoint = full_match.end();
+        let skip_marker = config.get_str("skip-marker").unwrap_or(DEFAULT_SKIP_MARKER).to_string();
 
-                    processed_content.push_str(options.site_path.as_str());
-                    processed_content.push_str(path.to_str().unwrap());
-                    if let Some(anchor) = file_link.anchor {
-                        processed_content.push_str("#");
-                        processed_content.push_str(anchor);
+        let output_format = config.get_enum("output-format", &OutputFormat::CHOICES, OutputFormat::Plain);
+
+        let mut ignore_chars: Vec<char> = Vec::new();
+        match config.value("ignore-chars") {
+            Some(toml::value::Value::String(value)) => ignore_chars = value.chars().collect(),
+            Some(toml::value::Value::Array(values)) => {
+                for value in values {
+                    match value {
+                        toml::value::Value::String(value) => ignore_chars.extend(value.chars()),
+                        value => config.invalid("ignore-chars", value, "a string")
                     }
-                } else {
-                    eprintln!("Error: Found request to replace link with '{}', but no chapter with that name found.", file_link.name.to_lowercase());
-                    return Err(ProcessorError::ChapterNotFound(file_link.name.to_lowercase()));
                 }
-            }
+            },
+            Some(value) => config.invalid("ignore-chars", value, "a string or an array of strings"),
+            None => {}
         }
 
-        if content.len() > last_endpoint {
-            processed_content.push_str(&content[last_endpoint..content.len()]);
-        }
+        let path_hint_resolution = config.get_enum("path-hint-resolution", &[
+            ("unique", PathHintResolution::Unique),
+            ("deepest", PathHintResolution::Deepest),
+            ("shallowest", PathHintResolution::Shallowest)
+        ], PathHintResolution::Unique);
 
-        Ok(processed_content)
-    }
-}
+        let mut variables: HashMap<&'static str, String> = HashMap::new();
+        variables.insert("book_title", book_config.book.title.clone().unwrap_or_default());
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::path::PathBuf;
-    use crate::{PathProcessor, PathProcessorOptions};
+        let profile = config.get_bool("profile").unwrap_or(false);
 
-    #[test]
-    fn test_process_chapter_replaces_links_to_top_level() {
-        let content = "[foo]({{#path_for Foo}})";
+        let missing_message = config.get_str("missing-message").unwrap_or(DEFAULT_MISSING_MESSAGE).to_string();
 
-        let mut chapter_mapping: HashMap<String, PathBuf> = HashMap::new();
-        chapter_mapping.insert("foo".to_string(), PathBuf::from("something/Foo.md"));
+        let default_target = config.get_str("default-target").map(|value| value.to_string());
+        let default_target_keyword = config.get_str("default-target-keyword").map(|value| value.to_string());
 
-        let subject = PathProcessor;
+        let warn_case_mismatch = config.get_bool("warn-case-mismatch").unwrap_or(false);
 
-        let received_chapter = subject.process_chapter(&content, &chapter_mapping, &processor_options("/")).unwrap();
+        let table_safe = config.get_bool("table-safe").unwrap_or(false);
 
-        let expected_chapter = "[foo](/something/Foo.md)";
+        let encoding = config.get_enum("encoding", &[
+            ("strict", UrlEncoding::Strict),
+            ("lenient", UrlEncoding::Lenient),
+            ("none", UrlEncoding::Off)
+        ], UrlEncoding::Off);
 
-        assert_eq!(received_chapter, expected_chapter.to_string());
-    }
+        let anchor_prefix = config.get_str("anchor-prefix").unwrap_or_default().to_string();
 
-    #[test]
-    fn test_process_chapter_replaces_links_to_anchor() {
-        let content = "[foo]({{#path_for Foo#bar}})";
+        let short_url_template = config.get_str("short-url-template").map(|value| value.to_string());
 
-        let mut chapter_mapping: HashMap<String, PathBuf> = HashMap::new();
-        chapter_mapping.insert("foo".to_string(), PathBuf::from("something/Foo.md"));
+        let process_part_titles = config.get_bool("process-part-titles").unwrap_or(false);
 
-        let subject = PathProcessor;
+        // Relative paths are resolved against the book root, not the build directory: the html
+        // renderer empties that after preprocessing, which would delete the file.
+        let diagnostics_file = config.get_str("diagnostics-file").map(PathBuf::from);
 
-        let received_chapter = subject.process_chapter(&content, &chapter_mapping, &processor_options("/root/")).unwrap();
+        let compat = config.get_enum("compat", &[("zola", Some(CompatSyntax::Zola))], None);
 
-        let expected_chapter = "[foo](/root/something/Foo.md#bar)";
+        let backslash_check = config.get_enum("backslash-check", &[
+            ("off", CheckLevel::Off),
+            ("warn", CheckLevel::Warn),
+            ("error", CheckLevel::Error)
+        ], CheckLevel::Off);
 
-        assert_eq!(received_chapter, expected_chapter.to_string());
+        let number_prefix = config.get_pattern("strip-number-prefix", DEFAULT_NUMBER_PREFIX);
+
+        let list_style = config.get_enum("list-style", &[
+            ("tight", ListStyle::Tight),
+            ("loose", ListStyle::Loose),
+            ("inline", ListStyle::Inline)
+        ], ListStyle::Tight);
+
+        let empty_result = match config.get_str("empty-result") {
+            Some("error") => EmptyResult::Error,
+            Some("empty") | None => EmptyResult::Empty,
+            Some(value) => match value.strip_prefix("placeholder:") {
+                Some(text) => EmptyResult::Placeholder(text.to_string()),
+                None => {
+                    config.warnings.push(format!("Unknown empty-result '{}', falling back to empty", value));
+                    EmptyResult::Empty
+                }
+            }
+        };
+
+        let validate_anchors = config.get_bool("validate-anchors").unwrap_or(false);
+
+        let id_algorithm = config.get_enum("id-algorithm", &[("v0.4", IdAlgorithm::V04), ("latest", IdAlgorithm::Latest)], IdAlgorithm::V04);
+
+        let missing_chapter = config.get_enum("missing-chapter", &[("error", MissingChapter::Error), ("warn", MissingChapter::Warn)], MissingChapter::Error);
+
+        let escape_check = config.get_enum("allow-escape", &[
+            ("allow", CheckLevel::Off),
+            ("warn", CheckLevel::Warn),
+            ("error", CheckLevel::Error)
+        ], CheckLevel::Warn);
+
+        let report_only = config.get_bool("report-only").unwrap_or(false);
+
+        let draft_pattern = config.get_pattern("lenient-in-drafts", DEFAULT_DRAFT_PATTERN);
+        let lenient_in_drafts = draft_pattern.is_some();
+
+        let mut mtime_format = DEFAULT_MTIME_FORMAT.to_string();
+        if let Some(value) = config.get_str("mtime-format") {
+            // chrono panics when asked to display an invalid format, so check it up front.
+            if StrftimeItems::new(value).any(|item| item == Item::Error) {
+                config.warnings.push(format!("Invalid mtime-format '{}', falling back to {}", value, DEFAULT_MTIME_FORMAT));
+            } else {
+                mtime_format = value.to_string();
+            }
+        }
+
+        let ambiguous_anchor = config.get_enum("ambiguous-anchor", &[
+            ("first", CheckLevel::Off),
+            ("warn", CheckLevel::Warn),
+            ("error", CheckLevel::Error)
+        ], if strict_mode { CheckLevel::Error } else { CheckLevel::Warn });
+
+        // Relative paths are resolved against the book root, like `diagnostics-file`.
+        let graph_file = config.get_str("emit-graph").map(PathBuf::from);
+
+        let mut resolution_order = DEFAULT_RESOLUTION_ORDER.to_vec();
+        match config.value("resolution-order") {
+            Some(toml::value::Value::Array(values)) => {
+                let mut configured: Vec<Lookup> = Vec::new();
+                for value in values {
+                    let lookup = Lookup::CHOICES.iter()
+                        .find(|(name, _)| Some(*name) == value.as_str())
+                        .map(|(_, lookup)| *lookup);
+                    match lookup {
+                        Some(lookup) if !configured.contains(&lookup) => configured.push(lookup),
+                        Some(_) => {},
+                        None => config.warnings.push(format!("Unknown resolution-order entry {}, ignoring it", value))
+                    }
+                }
+                if configured.is_empty() {
+                    config.warnings.push("resolution-order has no known entries, falling back to the default".to_string());
+                } else {
+                    resolution_order = configured;
+                }
+            },
+            Some(value) => config.invalid("resolution-order", value, "an array"),
+            None => {}
+        }
+
+        let manifest = config.get_str("manifest").map(PathBuf::from);
+
+        PathProcessorOptions {
+            site_path,
+            relative_links,
+            strict_mode,
+            skip_marker,
+            output_format,
+            ignore_chars,
+            path_hint_resolution,
+            variables,
+            profile,
+            missing_message,
+            src_dir: book_config.book.src.clone(),
+            book_root: PathBuf::new(),
+            mtime_format,
+            default_target,
+            default_target_keyword,
+            warn_case_mismatch,
+            table_safe,
+            encoding,
+            anchor_prefix,
+            short_url_template,
+            process_part_titles,
+            diagnostics_file,
+            compat,
+            backslash_check,
+            escape_check,
+            manifest,
+            backlinks: HashMap::new(),
+            number_prefix,
+            list_style,
+            empty_result,
+            validate_anchors,
+            id_algorithm,
+            anchors: HashMap::new(),
+            ambiguous_anchor,
+            missing_chapter,
+            report_only,
+            graph_file,
+            config_warnings: config.warnings,
+            lenient_in_drafts,
+            draft_pattern,
+            resolution_order
+        }
+    }
+
+    // Preprocessors that will run after us and leave directives unprocessed. mdbook 0.4.8 ignores
+    // `before` and `after`: it runs the default preprocessors first, then every `[preprocessor.*]`
+    // table in table order, so a `links` table of its own runs `links` again there.
+    fn ordering_warnings(&self, config: &Config) -> Vec<String> {
+        let preprocessors = match config.get("preprocessor").and_then(|value| value.as_table()) {
+            Some(preprocessors) => preprocessors,
+            None => return Vec::new()
+        };
+
+        let mut order: Vec<&str> = Vec::new();
+        if config.build.use_default_preprocessors {
+            order.extend(DEFAULT_PREPROCESSORS.iter());
+        }
+        order.extend(preprocessors.keys().map(|name| name.as_str()));
+
+        let ours = match order.iter().position(|name| *name == self.name()) {
+            Some(position) => position,
+            None => return Vec::new()
+        };
+        CONFLICTING_PREPROCESSORS.iter()
+            .filter(|conflicting| order[ours..].contains(conflicting))
+            .map(|conflicting| format!("The {} preprocessor is configured to run after {}, so directives it adds (e.g. from included files) won't be processed", conflicting, self.name()))
+            .collect()
+    }
+
+    fn profile_summary(&self, mut timings: Vec<(String, Duration)>, length: usize) -> String {
+        let total: Duration = timings.iter().map(|(_, duration)| *duration).sum();
+        timings.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+
+        let mut summary = format!("chapter-path processed {} chapters in {:?}; slowest:\n", timings.len(), total);
+        for (name, duration) in timings.iter().take(length) {
+            summary.push_str(&format!("  {:?}\t{}\n", duration, name));
+        }
+        summary
+    }
+
+    // Attaches the file and line of a directive, when the chapter has a source file.
+    fn locate(&self, diagnostic: Diagnostic, source_path: Option<&Path>, content: &str, offset: usize, options: &PathProcessorOptions) -> Diagnostic {
+        match source_path {
+            Some(source_path) => diagnostic.at(options.src_dir.join(source_path), line_number(content, offset)),
+            None => diagnostic
+        }
+    }
+
+    fn report(&self, diagnostic: Diagnostic, options: &PathProcessorOptions) {
+        let rendered = diagnostic.render(options.output_format);
+        if let Some(file) = &options.diagnostics_file {
+            let written = OpenOptions::new().append(true).create(true).open(file)
+                .and_then(|mut handle| writeln!(handle, "{}", rendered));
+            if let Err(error) = written {
+                let warning = Diagnostic::warning(format!("Unable to write to diagnostics file {}: {}", file.display(), error));
+                eprintln!("{}", warning.render(options.output_format));
+            }
+        }
+        eprintln!("{}", rendered);
+    }
+
+    fn report_config_warnings(&self, options: &PathProcessorOptions) {
+        for warning in &options.config_warnings {
+            self.report(Diagnostic::warning(warning.clone()), options);
+        }
+    }
+
+    // Empties the diagnostics file so it only holds this run's diagnostics.
+    fn start_diagnostics_file(&self, options: &PathProcessorOptions) {
+        if let Some(file) = &options.diagnostics_file {
+            let created = match file.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(())
+            }.and_then(|_| File::create(file));
+            if let Err(error) = created {
+                let warning = Diagnostic::warning(format!("Unable to create diagnostics file {}: {}", file.display(), error));
+                eprintln!("{}", warning.render(options.output_format));
+            }
+        }
+    }
+
+    // Reports the error, then hands it back in a form mdbook can propagate.
+    fn fail(&self, diagnostic: Diagnostic, options: &PathProcessorOptions) -> Error {
+        let message = diagnostic.message.clone();
+        self.report(diagnostic, options);
+        Error::msg(message)
+    }
+
+    fn missing_message(&self, name: &str, file: Option<&Path>, chapter_names: &ChapterMap, options: &PathProcessorOptions) -> String {
+        let file = file.and_then(|file| file.to_str()).unwrap_or_default();
+        let suggestion = self.suggest(name, chapter_names).unwrap_or_default();
+
+        options.missing_message
+            .replace("{name}", name)
+            .replace("{file}", file)
+            .replace("{suggestion}", suggestion)
+    }
+
+    // The known chapter name closest to `name`, if any is close enough to be a likely typo.
+    fn suggest<'a>(&self, name: &str, chapter_names: &'a ChapterMap) -> Option<&'a str> {
+        self.suggestions(name, chapter_names).first().map(|(_, key, _)| key.as_str())
+    }
+
+    // The closest chapter to a lowercased `name`, but only when no other chapter is just as close.
+    fn confident_suggestion<'a>(&self, name: &str, chapter_names: &'a ChapterMap) -> Option<&'a ChapterEntry> {
+        match self.suggestions(name, chapter_names).as_slice() {
+            [(distance, _, entry)] => Some(*entry).filter(|_| *distance > 0),
+            [(first, _, entry), (second, _, _), ..] if first < second => Some(*entry),
+            _ => None
+        }
+    }
+
+    // Chapters close enough to `name` to be likely typos, closest first.
+    fn suggestions<'a>(&self, name: &str, chapter_names: &'a ChapterMap) -> Vec<(usize, &'a String, &'a ChapterEntry)> {
+        if name.starts_with('@') {
+            return Vec::new();
+        }
+
+        let max_distance = std::cmp::max(2, name.chars().count() / 3);
+        let mut suggestions: Vec<(usize, &String, &ChapterEntry)> = chapter_names.iter()
+            .map(|(key, entry)| (edit_distance(name, key), key, entry))
+            .filter(|(distance, _, _)| *distance <= max_distance)
+            .collect();
+        suggestions.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        suggestions
+    }
+
+    /// Corrects references to chapters that don't exist when exactly one existing chapter is a
+    /// likely typo fix, e.g. `{{#path_for Instalation}}` to `{{#path_for Installation}}`.
+    ///
+    /// `book` should be loaded straight from the source files (e.g. with `MDBook::load`) in `root`,
+    /// so the returned content can be written back over them. Only chapters that changed are returned.
+    pub fn fix_references(&self, root: &Path, config: &Config, book: &Book) -> Result<Vec<FixedChapter>, Error> {
+        self.rewrite_references(root, config, book, |content, chapter_names, options| self.fix_chapter(content, chapter_names, options))
+    }
+
+    /// Rewrites references to the chapter name they match as it's written in SUMMARY.md, e.g.
+    /// `{{#path_for getting started}}` to `{{#path_for Getting Started}}`.
+    ///
+    /// Like [`PathProcessor::fix_references`], `book` should be loaded straight from the source files
+    /// in `root`, and only chapters that changed are returned.
+    pub fn normalize_references(&self, root: &Path, config: &Config, book: &Book) -> Result<Vec<FixedChapter>, Error> {
+        self.rewrite_references(root, config, book, |content, chapter_names, options| self.normalize_chapter(content, chapter_names, options))
+    }
+
+    // Applies `rewrite` to every chapter loaded from a file.
+    fn rewrite_references<F>(&self, root: &Path, config: &Config, book: &Book, rewrite: F) -> Result<Vec<FixedChapter>, Error>
+        where F: Fn(&str, &ChapterMap, &PathProcessorOptions) -> (String, Vec<ReferenceFix>) {
+        let options = self.options_for_root(config, root);
+        self.report_config_warnings(&options);
+        let known_chapters = self.chapter_names(book, &options)?;
+
+        let mut fixed_chapters: Vec<FixedChapter> = Vec::new();
+        for item in book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(source_path) = &chapter.source_path {
+                    let (content, fixes) = rewrite(&chapter.content, &known_chapters, &options);
+                    if !fixes.is_empty() {
+                        fixed_chapters.push(FixedChapter { source_path: source_path.clone(), content, fixes });
+                    }
+                }
+            }
+        }
+        Ok(fixed_chapters)
+    }
+
+    fn fix_chapter(&self, content: &str, chapter_names: &ChapterMap, options: &PathProcessorOptions) -> (String, Vec<ReferenceFix>) {
+        self.rewrite_chapter(content, |name| {
+            // Only references that don't resolve are fixed, however they resolve otherwise.
+            match self.resolve(&FileLink { name, anchor: None }, chapter_names, options) {
+                Err(ProcessorError::ChapterNotFound(_)) => {},
+                _ => return None
+            }
+            self.confident_suggestion(&self.chapter_key(name, options), chapter_names).map(|entry| entry.name.clone())
+        })
+    }
+
+    fn normalize_chapter(&self, content: &str, chapter_names: &ChapterMap, options: &PathProcessorOptions) -> (String, Vec<ReferenceFix>) {
+        self.rewrite_chapter(content, |name| {
+            chapter_names.get(&self.chapter_key(name, options))
+                .filter(|entry| entry.name != name)
+                .map(|entry| entry.name.clone())
+        })
+    }
+
+    // Replaces the chapter name of every directive `rewrite` returns a new name for. Path hints and
+    // directives without a name are left alone.
+    fn rewrite_chapter<F>(&self, content: &str, rewrite: F) -> (String, Vec<ReferenceFix>) where F: Fn(&str) -> Option<String> {
+        let mut fixed_content = String::new();
+        let mut fixes: Vec<ReferenceFix> = Vec::new();
+        let mut last_endpoint: usize = 0;
+
+        for directive in parse_directives(content) {
+            if directive.escaped || directive.name.is_empty() || directive.name.starts_with('@') || VARIABLE_KEYWORDS.contains(&directive.keyword) {
+                continue;
+            }
+            if let Some(name) = rewrite(directive.name) {
+                fixed_content.push_str(&content[last_endpoint..directive.span.start]);
+                last_endpoint = directive.span.end;
+
+                fixed_content.push_str(&format!("{{{{#{} {}", directive.keyword, name));
+                if let Some(anchor) = directive.anchor {
+                    fixed_content.push('#');
+                    fixed_content.push_str(anchor);
+                }
+                fixed_content.push_str("}}");
+
+                fixes.push(ReferenceFix {
+                    line: line_number(content, directive.span.start),
+                    from: directive.name.to_string(),
+                    to: name
+                });
+            }
+        }
+
+        fixed_content.push_str(&content[last_endpoint..]);
+        (fixed_content, fixes)
+    }
+
+    // The key a chapter name (or a reference to one) is matched by.
+    fn chapter_key(&self, name: &str, options: &PathProcessorOptions) -> String {
+        name.to_lowercase().chars().filter(|c| !options.ignore_chars.contains(c)).collect()
+    }
+
+    fn chapter_names(&self, book: &Book, options: &PathProcessorOptions) -> Result<ChapterMap, ProcessorError>{
+        let mut warnings: Vec<String> = Vec::new();
+        let mapping = self.collect_chapter_names(book, options, &mut warnings);
+        for warning in warnings {
+            self.report(Diagnostic::warning(warning), options);
+        }
+        mapping
+    }
+
+    // Manifests map chapter names to URLs, as a JSON object or a TOML table depending on the extension.
+    fn load_manifest(&self, path: &Path) -> Result<BTreeMap<String, String>, ProcessorError> {
+        let invalid = |reason: String| ProcessorError::InvalidManifest(path.to_path_buf(), reason);
+
+        let contents = fs::read_to_string(path).map_err(|error| invalid(error.to_string()))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::from_str(&contents).map_err(|error| invalid(error.to_string())),
+            _ => toml::from_str(&contents).map_err(|error| invalid(error.to_string()))
+        }
+    }
+
+    fn collect_chapter_names(&self, book: &Book, options: &PathProcessorOptions, warnings: &mut Vec<String>) -> Result<ChapterMap, ProcessorError>{
+        let mut mapping: ChapterMap = HashMap::new();
+
+        for item in book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                let path = match &chapter.path {
+                    Some(path) => path,
+                    None => {
+                        // Drafts have no path and no content. A path-less chapter with content was
+                        // most likely added by another preprocessor and can't be linked to.
+                        if !chapter.content.is_empty() {
+                            warnings.push(format!("Chapter {} has no path, so it can't be linked to", chapter.name));
+                        }
+                        continue;
+                    }
+                };
+                let path = match self.normalize_chapter_path(path) {
+                    Ok(path) => path,
+                    Err(reason) => {
+                        warnings.push(format!("Chapter {} at {} {}, so it can't be linked to", chapter.name, path.display(), reason));
+                        continue;
+                    }
+                };
+
+                let key = self.chapter_key(&chapter.name, options);
+                if let Some(existing) = mapping.get(&key) {
+                    let existing_name = &existing.name;
+                    let existing_path = &existing.path;
+                    if options.strict_mode {
+                        return Err(ProcessorError::DuplicateChapterNames(key));
+                    } else if existing_name.to_lowercase() != chapter.name.to_lowercase() {
+                        warnings.push(format!("Chapter names {} and {} are identical once ignore-chars are removed; using {}", existing_name, chapter.name, path.to_str().unwrap()));
+                    } else {
+                        warnings.push(format!("Found duplicate chapter name {} at {} (existing chapter at {})", chapter.name, path.to_str().unwrap(), existing_path.to_str().unwrap()));
+                    }
+                }
+                mapping.insert(key, ChapterEntry { name: chapter.name.clone(), path, url: None });
+            }
+        };
+
+        if let Some(manifest) = &options.manifest {
+            let position = |lookup: Lookup| options.resolution_order.iter().position(|configured| *configured == lookup);
+            let overrides = match (position(Lookup::Manifest), position(Lookup::Name)) {
+                (Some(manifest), Some(name)) => manifest < name,
+                (_, None) => true,
+                (None, Some(_)) => false
+            };
+            for (name, url) in self.load_manifest(manifest)? {
+                let key = self.chapter_key(&name, options);
+                if !overrides && mapping.contains_key(&key) {
+                    continue;
+                }
+                // Overridden chapters keep their path, so path hints still find them.
+                let path = mapping.get(&key).map(|existing| existing.path.clone()).unwrap_or_default();
+                mapping.insert(key, ChapterEntry { name, path, url: Some(url) });
+            }
+        }
+        Ok(mapping)
+    }
+
+    // Chapter paths are normally plain relative paths from SUMMARY.md, but chapters added by other
+    // preprocessors can have `./` prefixes, a leading `/` or `..` components.
+    fn normalize_chapter_path(&self, path: &Path) -> Result<PathBuf, &'static str> {
+        if path.to_str().is_none() {
+            return Err("has a path that isn't valid UTF-8");
+        }
+
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => normalized.push(part),
+                Component::CurDir | Component::RootDir => {},
+                Component::ParentDir => return Err("points outside the book"),
+                Component::Prefix(_) => return Err("has an absolute path")
+            }
+        }
+
+        if normalized.as_os_str().is_empty() {
+            return Err("has an empty path");
+        }
+        Ok(normalized)
+    }
+
+    // An empty name, or `default-target-keyword`, stands for the default target.
+    fn refers_to_default_target(&self, name: &str, options: &PathProcessorOptions) -> bool {
+        match &options.default_target_keyword {
+            _ if name.is_empty() => true,
+            Some(keyword) => self.chapter_key(name, options) == self.chapter_key(keyword, options),
+            None => false
+        }
+    }
+
+    fn resolve<'a>(&self, file_link: &FileLink, chapter_names: &'a ChapterMap, options: &PathProcessorOptions) -> Result<&'a ChapterEntry, ProcessorError> {
+        if let Some(hint) = file_link.name.strip_prefix('@') {
+            return self.resolve_path_hint(hint, chapter_names, options);
+        }
+
+        if self.refers_to_default_target(file_link.name, options) {
+            let default_target = options.default_target.as_deref().unwrap_or_default();
+            return chapter_names.get(&self.chapter_key(default_target, options))
+                .ok_or_else(|| ProcessorError::DefaultTargetNotFound(default_target.to_string()));
+        }
+
+        let key = self.chapter_key(file_link.name, options);
+        for lookup in &options.resolution_order {
+            let entry = match lookup {
+                Lookup::Manifest => chapter_names.get(&key).filter(|entry| entry.url.is_some()),
+                Lookup::Name => chapter_names.get(&key).filter(|entry| entry.url.is_none()),
+                // Only a unique match counts; an ambiguous one moves on to the next lookup.
+                Lookup::Path => self.resolve_path_hint(file_link.name, chapter_names, options).ok()
+            };
+            if let Some(entry) = entry {
+                return Ok(entry);
+            }
+        }
+        Err(ProcessorError::ChapterNotFound(file_link.name.to_lowercase()))
+    }
+
+    // Path hints match chapters whose path ends with the given components, or, when the hint starts
+    // with `/`, whose whole path is the hint. The extension is ignored, so `foo`, `foo.md` and the
+    // rendered `foo.html` all find `foo.md`.
+    fn resolve_path_hint<'a>(&self, hint: &str, chapter_names: &'a ChapterMap, options: &PathProcessorOptions) -> Result<&'a ChapterEntry, ProcessorError> {
+        let stem = PATH_HINT_EXTENSIONS.iter()
+            .find_map(|extension| hint.strip_suffix(extension))
+            .unwrap_or(hint);
+        let rooted = stem.strip_prefix('/');
+        let hint_path = Path::new(rooted.unwrap_or(stem));
+
+        let hint_matches = |path: &Path| match rooted {
+            Some(_) => path == hint_path,
+            None => path.ends_with(hint_path)
+        };
+        let mut matches: Vec<&ChapterEntry> = chapter_names.values()
+            .filter(|entry| {
+                let path = entry.path.with_extension("");
+                hint_matches(&path) || matches!(self.strip_number_prefix(&path, options), Some(stripped) if hint_matches(&stripped))
+            })
+            .collect();
+        matches.sort_by(|a, b| a.path.cmp(&b.path));
+        matches.dedup_by(|a, b| a.path == b.path);
+
+        let depth = |entry: &ChapterEntry| entry.path.components().count();
+        let candidates: Vec<&ChapterEntry> = match options.path_hint_resolution {
+            PathHintResolution::Unique => matches,
+            PathHintResolution::Deepest => {
+                let deepest = matches.iter().map(|path| depth(path)).max();
+                matches.into_iter().filter(|path| Some(depth(path)) == deepest).collect()
+            },
+            PathHintResolution::Shallowest => {
+                let shallowest = matches.iter().map(|path| depth(path)).min();
+                matches.into_iter().filter(|path| Some(depth(path)) == shallowest).collect()
+            }
+        };
+
+        match candidates.len() {
+            0 => Err(ProcessorError::ChapterNotFound(format!("@{}", hint))),
+            1 => Ok(candidates[0]),
+            _ => Err(ProcessorError::AmbiguousPathHint(hint.to_string(), candidates.into_iter().map(|entry| entry.path.clone()).collect()))
+        }
+    }
+
+    // Our directives, custom ones, and links in the configured compat syntax, in order.
+    fn directives<'a>(&self, content: &'a str, options: &PathProcessorOptions) -> Vec<ParsedDirective<'a>> {
+        let custom_keywords: Vec<&str> = self.handlers.keys().map(|keyword| keyword.as_str()).collect();
+        let mut directives = parse_directives_with(content, &custom_keywords);
+        if let Some(compat) = options.compat {
+            directives.extend(compat.directives(content));
+            directives.sort_by_key(|directive| directive.span.start);
+        }
+        directives
+    }
+
+    // `{{#backlinks_for}}` and the reference graph need every chapter's references before any chapter
+    // is processed, so they are resolved up front. References that don't resolve are skipped here and
+    // reported later, as are references from a chapter to itself.
+    fn collect_references(&self, book: &Book, chapter_names: &ChapterMap, options: &PathProcessorOptions) -> Vec<Reference> {
+        let mut references: Vec<Reference> = Vec::new();
+
+        for item in book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                let path = match chapter.path.as_ref().and_then(|path| self.normalize_chapter_path(path).ok()) {
+                    Some(path) => path,
+                    None => continue
+                };
+                if !options.skip_marker.is_empty() && chapter.content.contains(options.skip_marker.as_str()) {
+                    continue;
+                }
+
+                let first_from_chapter = references.len();
+                for directive in self.directives(&chapter.content, options) {
+                    if directive.escaped || !(directive.keyword == "path_for" || directive.keyword == "short_path_for") {
+                        continue;
+                    }
+                    // Hints with backslashes link wherever they were corrected to; the warning is reported by processing.
+                    let name = match self.check_backslashes(directive.name, directive.span.start, options, &mut Vec::new()) {
+                        Ok(name) => name,
+                        Err(_) => continue
+                    };
+                    let file_link = FileLink { name: &name, anchor: directive.anchor };
+                    let target = match self.resolve(&file_link, chapter_names, options) {
+                        Ok(target) => target,
+                        Err(_) => continue
+                    };
+                    if target.path == path {
+                        continue;
+                    }
+
+                    match references[first_from_chapter..].iter_mut().find(|reference| reference.target.name == target.name) {
+                        Some(reference) => reference.count += 1,
+                        None => references.push(Reference {
+                            source: ChapterEntry { name: chapter.name.clone(), path: path.clone(), url: None },
+                            target: target.clone(),
+                            count: 1
+                        })
+                    }
+                }
+            }
+        }
+
+        references
+    }
+
+    fn backlinks(&self, references: &[Reference], options: &PathProcessorOptions) -> HashMap<String, Vec<ChapterEntry>> {
+        let mut backlinks: HashMap<String, Vec<ChapterEntry>> = HashMap::new();
+        for reference in references {
+            backlinks.entry(self.chapter_key(&reference.target.name, options)).or_default().push(reference.source.clone());
+        }
+        backlinks
+    }
+
+    // Chapters and the references between them, as DOT if `file` ends in `.dot` and as JSON otherwise.
+    fn reference_graph(&self, book: &Book, references: &[Reference], file: &Path) -> String {
+        let chapters: Vec<(String, PathBuf)> = book.iter().filter_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter.path.as_ref()
+                .and_then(|path| self.normalize_chapter_path(path).ok())
+                .map(|path| (chapter.name.clone(), path)),
+            _ => None
+        }).collect();
+        // Manifest entries aren't chapters, so references to them are left out.
+        let edges: Vec<&Reference> = references.iter()
+            .filter(|reference| chapters.iter().any(|(_, path)| *path == reference.target.path))
+            .collect();
+
+        if file.extension().and_then(|extension| extension.to_str()) == Some("dot") {
+            let mut graph = String::from("digraph chapters {\n");
+            for (name, path) in &chapters {
+                graph.push_str(&format!("    {:?} [label={:?}];\n", path.to_string_lossy(), name));
+            }
+            for reference in edges {
+                graph.push_str(&format!("    {:?} -> {:?} [label=\"{}\"];\n", reference.source.path.to_string_lossy(), reference.target.path.to_string_lossy(), reference.count));
+            }
+            graph.push_str("}\n");
+            graph
+        } else {
+            let nodes: Vec<serde_json::Value> = chapters.iter()
+                .map(|(name, path)| json!({ "name": name, "path": path.to_string_lossy() }))
+                .collect();
+            let edges: Vec<serde_json::Value> = edges.iter()
+                .map(|reference| json!({
+                    "from": reference.source.path.to_string_lossy(),
+                    "to": reference.target.path.to_string_lossy(),
+                    "count": reference.count
+                }))
+                .collect();
+            format!("{:#}\n", json!({ "nodes": nodes, "edges": edges }))
+        }
+    }
+
+    // Chapters are read as we get them, after `{{#include}}`s have been expanded, so headings from
+    // included files count too.
+    fn collect_anchors(&self, book: &Book, options: &PathProcessorOptions) -> HashMap<PathBuf, HeadingIds> {
+        let mut anchors: HashMap<PathBuf, HeadingIds> = HashMap::new();
+
+        for item in book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(path) = chapter.path.as_ref().and_then(|path| self.normalize_chapter_path(path).ok()) {
+                    let mut ids = self.heading_ids(&chapter.content, options);
+                    for id in self.footnote_ids(&chapter.content) {
+                        *ids.entry(id).or_insert(0) += 1;
+                    }
+                    anchors.insert(path, ids);
+                }
+            }
+        }
+
+        anchors
+    }
+
+    // The ids mdBook's HTML renderer gives the headings in `content`: the heading text run through
+    // the configured `id-algorithm`, with `-1`, `-2`, ... appended to repeats.
+    fn heading_ids(&self, content: &str, options: &PathProcessorOptions) -> HeadingIds {
+        let regex = Regex::new(r"<h\d>(.*?)</h\d>").unwrap();
+        let html = render_markdown(content, false);
+
+        let mut ids: HeadingIds = HashMap::new();
+        let mut id_counter: HashMap<String, usize> = HashMap::new();
+        for capture in regex.captures_iter(&html) {
+            let raw_id = options.id_algorithm.heading_id(&capture[1]);
+            let id_count = id_counter.entry(raw_id.clone()).or_insert(0);
+            if *id_count > 0 {
+                ids.insert(format!("{}-{}", raw_id, id_count), 1);
+            }
+            *id_count += 1;
+            ids.insert(raw_id, *id_count);
+        }
+        ids
+    }
+
+    // Footnote definitions are rendered with their label, as written, for an id: `[^note]: ...` can
+    // be linked to as `#note`. Definition lists aren't supported by mdBook's markdown renderer, so
+    // there are no term ids to collect.
+    fn footnote_ids(&self, content: &str) -> Vec<String> {
+        let regex = Regex::new(r#"<div class="footnote-definition" id="([^"]*)">"#).unwrap();
+        let html = render_markdown(content, false);
+
+        regex.captures_iter(&html).map(|capture| capture[1].to_string()).collect()
+    }
+
+    fn check_anchor(&self, entry: &ChapterEntry, anchor: Option<&str>, offset: usize, options: &PathProcessorOptions, warnings: &mut Vec<DirectiveWarning>) -> Result<(), ProcessorError> {
+        let anchor = match anchor {
+            Some(anchor) if options.validate_anchors && entry.url.is_none() && !is_line_anchor(anchor) => anchor,
+            _ => return Ok(())
+        };
+
+        let count = match options.anchors.get(&entry.path).and_then(|ids| ids.get(anchor)) {
+            Some(count) => *count,
+            None => return Err(ProcessorError::AnchorNotFound(entry.name.clone(), anchor.to_string()))
+        };
+        if count < 2 {
+            return Ok(());
+        }
+
+        // mdBook gives the id to the first of the headings, so that's where the link goes.
+        let error = ProcessorError::AmbiguousAnchor(entry.name.clone(), anchor.to_string(), count);
+        match options.ambiguous_anchor {
+            CheckLevel::Off => Ok(()),
+            CheckLevel::Warn => {
+                warnings.push(DirectiveWarning { message: format!("{} Linking to the first one.", error), offset, missing: None });
+                Ok(())
+            },
+            CheckLevel::Error => Err(error)
+        }
+    }
+
+    // Links to each chapter that references `entry`, in book order.
+    fn backlinks_list(&self, entry: &ChapterEntry, current_path: Option<&Path>, options: &PathProcessorOptions) -> Result<String, ProcessorError> {
+        let links: Vec<String> = options.backlinks.get(&self.chapter_key(&entry.name, options))
+            .map(|sources| sources.iter()
+                .map(|source| format!("[{}]({})", source.name, self.chapter_url(&source.path, None, current_path, options)))
+                .collect())
+            .unwrap_or_default();
+        self.format_list(&links, options).ok_or_else(|| ProcessorError::EmptyResult("backlinks_for".to_string(), entry.name.clone()))
+    }
+
+    // Every directive that expands to several items lays them out here, so they all follow `list-style`
+    // and `empty-result`. `None` if there are no items and that's an error.
+    fn format_list(&self, items: &[String], options: &PathProcessorOptions) -> Option<String> {
+        if items.is_empty() {
+            return match &options.empty_result {
+                EmptyResult::Error => None,
+                EmptyResult::Empty => Some(String::new()),
+                EmptyResult::Placeholder(text) => Some(text.clone())
+            };
+        }
+        Some(match options.list_style {
+            ListStyle::Tight => items.iter().map(|item| format!("- {}", item)).collect::<Vec<String>>().join("\n"),
+            ListStyle::Loose => items.iter().map(|item| format!("- {}", item)).collect::<Vec<String>>().join("\n\n"),
+            ListStyle::Inline => items.join(", ")
+        })
+    }
+
+    // `01-guide/02-setup` becomes `guide/setup`, if a number prefix pattern is configured.
+    fn strip_number_prefix(&self, path: &Path, options: &PathProcessorOptions) -> Option<PathBuf> {
+        let regex = options.number_prefix.as_ref()?;
+        Some(path.components()
+            .map(|component| regex.replace(&component.as_os_str().to_string_lossy(), "").into_owned())
+            .collect())
+    }
+
+    #[cfg(test)]
+    fn process_chapter(&self, content: &str, chapter_names: &ChapterMap, options: &PathProcessorOptions) -> Result<String, ProcessorError> {
+        self.process_chapter_located(content, None, chapter_names, options, &mut Vec::new()).map_err(|located| located.error)
+    }
+
+    // `current_path` is the path of the chapter being processed, if it has one.
+    fn process_chapter_located(&self, content: &str, current_path: Option<&Path>, chapter_names: &ChapterMap, options: &PathProcessorOptions, warnings: &mut Vec<DirectiveWarning>) -> Result<String, DirectiveError> {
+        // Chapters containing the skip marker are left alone, apart from removing the marker itself.
+        if !options.skip_marker.is_empty() && content.contains(options.skip_marker.as_str()) {
+            return Ok(content.replace(options.skip_marker.as_str(), ""));
+        }
+
+        // SUMMARY.md may spell the path as `./guide/../intro.md`; relative links are computed from its canonical form.
+        let current_path = current_path.map(lexical_normalize);
+        let current_path = current_path.as_deref();
+
+        let mut processed_content = String::new();
+
+        let mut last_endpoint: usize = 0;
+
+        // Pages often reference the same chapter many times; resolve each distinct argument once.
+        let mut resolved_urls: HashMap<(&str, &str, Option<&str>), String> = HashMap::new();
+
+        let directives = self.directives(content, options);
+        let custom_keywords: Vec<&str> = self.handlers.keys().map(|keyword| keyword.as_str()).collect();
+        if let Some((offset, text)) = unbalanced_directive(content, &custom_keywords, &directives) {
+            return Err(DirectiveError { error: ProcessorError::UnbalancedBraces(text.to_string()), offset });
+        }
+
+        for directive in directives {
+            processed_content.push_str(&content[last_endpoint..directive.span.start]);
+            last_endpoint = directive.span.end;
+
+            if directive.escaped {
+                processed_content.push_str(&content[directive.span.start + 1..directive.span.end]);
+                continue;
+            }
+            if VARIABLE_KEYWORDS.contains(&directive.keyword) {
+                match (directive.name, directive.anchor) {
+                    ("", None) => processed_content.push_str(options.variables.get(directive.keyword).map(String::as_str).unwrap_or_default()),
+                    _ => processed_content.push_str(&content[directive.span.clone()])
+                }
+                continue;
+            }
+            if let Some(anchor) = directive.anchor {
+                let reference = format!("{}#{}", directive.name, anchor);
+                FileLink::from_string(&reference).map_err(|error| DirectiveError { error, offset: directive.span.start })?;
+            }
+
+            // Custom handlers may not be pure (counters, footnotes), so they aren't memoized.
+            if let Some(handler) = self.handlers.get(directive.keyword) {
+                let context = DirectiveContext { processor: self, chapter_names, options, current_path };
+                let output = handler(&directive, &context)
+                    .map_err(|error| DirectiveError { error, offset: directive.span.start })?;
+                processed_content.push_str(&output);
+                continue;
+            }
+
+            let key = (directive.keyword, directive.name, directive.anchor);
+            if let Entry::Vacant(slot) = resolved_urls.entry(key) {
+                let name = self.check_backslashes(directive.name, directive.span.start, options, warnings)
+                    .map_err(|error| DirectiveError { error, offset: directive.span.start })?;
+                let file_link = FileLink { name: &name, anchor: directive.anchor };
+                let entry = match self.resolve(&file_link, chapter_names, options) {
+                    Ok(entry) => entry,
+                    // Not memoized, so every occurrence is counted.
+                    Err(ProcessorError::ChapterNotFound(missing)) if options.missing_chapter == MissingChapter::Warn => {
+                        let message = ProcessorError::ChapterNotFound(missing.clone()).to_string();
+                        warnings.push(DirectiveWarning { message, offset: directive.span.start, missing: Some(missing) });
+                        processed_content.push_str(&content[directive.span.clone()]);
+                        continue;
+                    },
+                    Err(error) => return Err(DirectiveError { error, offset: directive.span.start })
+                };
+                if let Some(message) = self.case_mismatch(&file_link, entry, options) {
+                    warnings.push(DirectiveWarning { message, offset: directive.span.start, missing: None });
+                }
+                if directive.keyword == "path_for" || directive.keyword == "short_path_for" {
+                    self.check_anchor(entry, file_link.anchor, directive.span.start, options, warnings)
+                        .map_err(|error| DirectiveError { error, offset: directive.span.start })?;
+                }
+                let url = match directive.keyword {
+                    "short_path_for" => self.short_url(entry, file_link.anchor, options)
+                        .map_err(|error| DirectiveError { error, offset: directive.span.start })?,
+                    "backlinks_for" => self.backlinks_list(entry, current_path, options)
+                        .map_err(|error| DirectiveError { error, offset: directive.span.start })?,
+                    "mtime_for" => match self.modified_time(entry, options) {
+                        Ok(modified) => modified,
+                        Err(message) => {
+                            warnings.push(DirectiveWarning { message, offset: directive.span.start, missing: None });
+                            String::new()
+                        }
+                    },
+                    _ => {
+                        if entry.url.is_none() {
+                            self.check_escape(entry, current_path, directive.span.start, options, warnings)
+                                .map_err(|error| DirectiveError { error, offset: directive.span.start })?;
+                        }
+                        self.entry_url(entry, file_link.anchor, current_path, options)
+                    }
+                };
+                slot.insert(url);
+            }
+
+            processed_content.push_str(&resolved_urls[&key]);
+        }
+
+        if content.len() > last_endpoint {
+            processed_content.push_str(&content[last_endpoint..content.len()]);
+        }
+
+        Ok(processed_content)
+    }
+
+    // Part titles aren't chapter content, so directives in them are only processed when
+    // `process-part-titles` is set. Otherwise they are left as-is, with a warning.
+    fn process_part_title(&self, title: &str, chapter_names: &ChapterMap, options: &PathProcessorOptions, warnings: &mut Vec<String>) -> Result<Option<String>, DirectiveError> {
+        if parse_directives(title).iter().all(|directive| directive.escaped) {
+            return Ok(None);
+        }
+
+        if !options.process_part_titles {
+            warnings.push(format!("Part title '{}' contains directives, which are only processed with process-part-titles = true", title));
+            return Ok(None);
+        }
+
+        let mut directive_warnings: Vec<DirectiveWarning> = Vec::new();
+        let processed = self.process_chapter_located(title, None, chapter_names, options, &mut directive_warnings)?;
+        warnings.extend(directive_warnings.into_iter().map(|warning| format!("In part title '{}': {}", title, warning.message)));
+        Ok(Some(processed))
+    }
+
+    // Only path hints are checked: a backslash can legitimately appear in a chapter name.
+    fn check_backslashes<'a>(&self, name: &'a str, offset: usize, options: &PathProcessorOptions, warnings: &mut Vec<DirectiveWarning>) -> Result<Cow<'a, str>, ProcessorError> {
+        if options.backslash_check == CheckLevel::Off || !name.starts_with('@') || !name.contains('\\') {
+            return Ok(Cow::Borrowed(name));
+        }
+
+        let corrected = name.replace('\\', "/");
+        match options.backslash_check {
+            CheckLevel::Error => Err(ProcessorError::BackslashInPathHint(name.to_string(), corrected)),
+            _ => {
+                warnings.push(DirectiveWarning { message: ProcessorError::BackslashInPathHint(name.to_string(), corrected.clone()).to_string(), offset, missing: None });
+                Ok(Cow::Owned(corrected))
+            }
+        }
+    }
+
+    // The chapter file's modification time, in UTC. Errors are warnings: a missing date shouldn't
+    // fail the build.
+    fn modified_time(&self, entry: &ChapterEntry, options: &PathProcessorOptions) -> Result<String, String> {
+        if entry.url.is_some() {
+            return Err(format!("Chapter {} comes from the manifest, so it has no modification time", entry.name));
+        }
+
+        let file = options.book_root.join(&options.src_dir).join(&entry.path);
+        let modified = fs::metadata(&file).and_then(|metadata| metadata.modified())
+            .map_err(|error| format!("Unable to read the modification time of {}: {}", file.display(), error))?;
+        Ok(DateTime::<Utc>::from(modified).format(&options.mtime_format).to_string())
+    }
+
+    // Relative links climb out of the referencing chapter's directory with `..`. If they climb higher
+    // than that directory is deep, e.g. from a chapter whose own path starts with `..`, the link
+    // leaves the site.
+    fn check_escape(&self, entry: &ChapterEntry, current_path: Option<&Path>, offset: usize, options: &PathProcessorOptions, warnings: &mut Vec<DirectiveWarning>) -> Result<(), ProcessorError> {
+        if options.escape_check == CheckLevel::Off {
+            return Ok(());
+        }
+
+        let (start, link) = match current_path {
+            Some(from) if options.relative_links => {
+                let from_dir = from.parent().unwrap_or_else(|| Path::new(""));
+                (from_dir.to_path_buf(), relative_path(from_dir, &entry.path))
+            },
+            _ => (PathBuf::new(), entry.path.clone())
+        };
+
+        let mut depth: isize = 0;
+        let mut escapes = false;
+        for component in start.components().chain(link.components()) {
+            match component {
+                Component::ParentDir => depth -= 1,
+                Component::Normal(_) => depth += 1,
+                _ => {}
+            }
+            escapes |= depth < 0;
+        }
+        if !escapes {
+            return Ok(());
+        }
+
+        let error = ProcessorError::EscapesRoot(entry.name.clone(), self.chapter_url(&entry.path, None, current_path, options));
+        match options.escape_check {
+            CheckLevel::Error => Err(error),
+            _ => {
+                warnings.push(DirectiveWarning { message: error.to_string(), offset, missing: None });
+                Ok(())
+            }
+        }
+    }
+
+    // With `warn-case-mismatch`, a message when a reference only matched its chapter case-insensitively.
+    fn case_mismatch(&self, file_link: &FileLink, entry: &ChapterEntry, options: &PathProcessorOptions) -> Option<String> {
+        if !options.warn_case_mismatch || self.refers_to_default_target(file_link.name, options) || file_link.name.starts_with('@') {
+            return None;
+        }
+
+        let strip = |name: &str| -> String { name.chars().filter(|c| !options.ignore_chars.contains(c)).collect() };
+        if strip(file_link.name) == strip(&entry.name) {
+            return None;
+        }
+
+        Some(format!("Reference to '{}' differs in case from the chapter name; use '{}'", file_link.name, entry.name))
+    }
+
+}
+
+// Line-number anchors like `#L42` are injected by highlighting plugins, not derived from headings.
+fn is_line_anchor(anchor: &str) -> bool {
+    let digits = match anchor.strip_prefix('L') {
+        Some(digits) => digits,
+        None => return false
+    };
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+// Percent-encodes the UTF-8 bytes of every character `keep` rejects.
+fn percent_encode(value: &str, keep: impl Fn(char) -> bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for c in value.chars() {
+        if keep(c) {
+            encoded.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+// RFC 3986 `pchar`, minus `%`: unreserved characters, sub-delims, `:` and `@`.
+fn is_pchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-._~!$&'()*+,;=:@".contains(c)
+}
+
+// Characters that end a markdown link destination or an HTML attribute early.
+fn breaks_link(c: char) -> bool {
+    c.is_whitespace() || c.is_control() || "\"<>`".contains(c)
+}
+
+// The path to `to` from the directory `from_dir`, both relative to the book's source directory.
+fn relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from_dir.components().collect();
+    let to_components: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(to_components.iter()).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to_components[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+// Removes `.` components and resolves `..` against the preceding component, without touching the
+// file system. Leading `..`s are kept, so paths outside the book still look that way.
+fn lexical_normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir if matches!(normalized.components().next_back(), Some(Component::Normal(_))) => {
+                normalized.pop();
+            },
+            component => normalized.push(component)
+        }
+    }
+    normalized
+}
+
+// 1-based line number of the given byte offset.
+fn line_number(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+// Levenshtein distance between two strings, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(*[previous[j + 1] + 1, current[j] + 1, substitution].iter().min().unwrap());
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use mdbook::book::{Book, BookItem, Chapter};
+    use mdbook::Config;
+    use mdbook::preprocess::{LinkPreprocessor, Preprocessor, PreprocessorContext};
+    use regex::Regex;
+    use crate::{ChapterEntry, CheckLevel, ChapterMap, CompatSyntax, EmptyResult, IdAlgorithm, ListStyle, Lookup, MissingChapter, ParsedDirective, PathHintResolution, ReferenceFix, PathProcessor, PathProcessorOptions, ProcessorError, UrlEncoding};
+    use crate::diagnostics::{Diagnostic, OutputFormat};
+
+    #[test]
+    fn test_process_chapter_replaces_links_to_top_level() {
+        let content = "[foo]({{#path_for Foo}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "something/Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(&content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        let expected_chapter = "[foo](/something/Foo.md)";
+
+        assert_eq!(received_chapter, expected_chapter.to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_replaces_links_to_anchor() {
+        let content = "[foo]({{#path_for Foo#bar}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "something/Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(&content, &chapter_mapping, &processor_options("/root/")).unwrap();
+
+        let expected_chapter = "[foo](/root/something/Foo.md#bar)";
+
+        assert_eq!(received_chapter, expected_chapter.to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_skips_chapters_with_default_skip_marker() {
+        let content = "<!-- chapter-path: skip -->\n[foo]({{#path_for Foo}})";
+
+        let chapter_mapping: ChapterMap = HashMap::new();
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "\n[foo]({{#path_for Foo}})".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_honors_custom_skip_marker() {
+        let content = "%% no-chapter-paths %%\n[foo]({{#path_for Foo}})";
+
+        let chapter_mapping: ChapterMap = HashMap::new();
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.skip_marker = "%% no-chapter-paths %%".to_string();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "\n[foo]({{#path_for Foo}})".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_ignores_default_skip_marker_when_custom_marker_set() {
+        let content = "<!-- chapter-path: skip -->\n[foo]({{#path_for Foo}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.skip_marker = "%% no-chapter-paths %%".to_string();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "<!-- chapter-path: skip -->\n[foo](/Foo.md)".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_leaves_other_directives_untouched() {
+        let content = "{{#include foo.rs}}\n[foo]({{#path_for Foo}})\n{{#path_forX Foo}}\n{{#path_for_something_else}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        let expected_chapter = "{{#include foo.rs}}\n[foo](/Foo.md)\n{{#path_forX Foo}}\n{{#path_for_something_else}}";
+
+        assert_eq!(received_chapter, expected_chapter.to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_leaves_adjacent_directives_untouched() {
+        let content = "{{#include foo.rs}}{{#path_for Foo}}{{#playground bar.rs}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "{{#include foo.rs}}/Foo.md{{#playground bar.rs}}".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_missing_chapter_renders_as_json() {
+        let content = "[foo]({{#path_for Foo}})";
+
+        let chapter_mapping: ChapterMap = HashMap::new();
+
+        let subject = PathProcessor::new();
+
+        let error = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap_err();
+
+        assert_eq!(error, ProcessorError::ChapterNotFound("foo".to_string()));
+        assert_eq!(
+            Diagnostic::error(error.to_string()).render(OutputFormat::Json),
+            r#"{"level":"error","message":"Found request to replace link with 'foo', but no chapter with that name found."}"#
+        );
+    }
+
+    #[test]
+    fn test_process_chapter_strips_ignore_chars_from_references() {
+        let content = "[quoted]({{#path_for \"Quoted\" Name}})";
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.ignore_chars = vec!['\u{201C}', '\u{201D}', '"'];
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert(subject.chapter_key("\u{201C}Quoted\u{201D} Name", &options), chapter("\u{201C}Quoted\u{201D} Name", "quoted.md"));
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "[quoted](/quoted.md)".to_string());
+    }
+
+    #[test]
+    fn test_chapter_names_strips_ignore_chars_from_names() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Widget\u{2122} Setup", String::new(), "widget.md", Vec::new()));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.ignore_chars = vec!['\u{2122}'];
+
+        let chapter_mapping = subject.chapter_names(&book, &options).unwrap();
+
+        assert_eq!(chapter_mapping.get("widget setup"), Some(&chapter("Widget\u{2122} Setup", "widget.md")));
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_unique_errors_on_multiple_matches() {
+        let subject = PathProcessor::new();
+
+        let error = subject.process_chapter("{{#path_for @setup}}", &setup_chapters(), &processor_options("/")).unwrap_err();
+
+        assert_eq!(error, ProcessorError::AmbiguousPathHint("setup".to_string(), vec![
+            PathBuf::from("guide/advanced/setup.md"),
+            PathBuf::from("guide/setup.md"),
+            PathBuf::from("setup.md")
+        ]));
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_unique_resolves_single_match() {
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter("{{#path_for @advanced/setup}}", &setup_chapters(), &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "/guide/advanced/setup.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_deepest() {
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.path_hint_resolution = PathHintResolution::Deepest;
+
+        let received_chapter = subject.process_chapter("{{#path_for @setup.md#install}}", &setup_chapters(), &options).unwrap();
+
+        assert_eq!(received_chapter, "/guide/advanced/setup.md#install".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_shallowest() {
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.path_hint_resolution = PathHintResolution::Shallowest;
+
+        let received_chapter = subject.process_chapter("{{#path_for @setup}}", &setup_chapters(), &options).unwrap();
+
+        assert_eq!(received_chapter, "/setup.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_matches_whole_components_only() {
+        let subject = PathProcessor::new();
+
+        let error = subject.process_chapter("{{#path_for @tup}}", &setup_chapters(), &processor_options("/")).unwrap_err();
+
+        assert_eq!(error, ProcessorError::ChapterNotFound("@tup".to_string()));
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_ignores_extension() {
+        let subject = PathProcessor::new();
+
+        for hint in &["guide/teardown", "guide/teardown.md", "guide/teardown.html"] {
+            let content = format!("{{{{#path_for @{}}}}}", hint);
+
+            let received_chapter = subject.process_chapter(&content, &setup_chapters(), &processor_options("/")).unwrap();
+
+            assert_eq!(received_chapter, "/guide/teardown.md".to_string(), "hint: {}", hint);
+        }
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_keeps_other_dots() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("release notes".to_string(), chapter("release notes", "releases/v1.2.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter("{{#path_for @v1.2.html}}", &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "/releases/v1.2.md".to_string());
+    }
+
+    fn setup_chapters() -> ChapterMap {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("setup", "setup.md"));
+        chapter_mapping.insert("guide setup".to_string(), chapter("guide setup", "guide/setup.md"));
+        chapter_mapping.insert("advanced setup".to_string(), chapter("advanced setup", "guide/advanced/setup.md"));
+        chapter_mapping.insert("teardown".to_string(), chapter("teardown", "guide/teardown.md"));
+        chapter_mapping
+    }
+
+    #[test]
+    fn test_process_chapter_substitutes_book_title() {
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.variables.insert("book_title", "The Book".to_string());
+
+        let received_chapter = subject.process_chapter("Welcome to {{#book_title}}!", &HashMap::new(), &options).unwrap();
+
+        assert_eq!(received_chapter, "Welcome to The Book!".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_substitutes_chapter_count() {
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.variables.insert("chapter_count", "12".to_string());
+
+        let received_chapter = subject.process_chapter("{{#chapter_count}} chapters, {{#chapter_counts}}", &HashMap::new(), &options).unwrap();
+
+        assert_eq!(received_chapter, "12 chapters, {{#chapter_counts}}".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_leaves_escaped_variables_as_text() {
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.variables.insert("book_title", "The Book".to_string());
+
+        let received_chapter = subject.process_chapter("Write \\{{#book_title}} to get {{#book_title}}.", &HashMap::new(), &options).unwrap();
+
+        assert_eq!(received_chapter, "Write {{#book_title}} to get The Book.".to_string());
+    }
+
+    #[test]
+    fn test_process_part_title_warns_about_variables() {
+        let subject = PathProcessor::new();
+
+        let mut warnings = Vec::new();
+        let processed = subject.process_part_title("About {{#book_title}}", &HashMap::new(), &processor_options("/"), &mut warnings).ok().unwrap();
+
+        assert_eq!(processed, None);
+        assert_eq!(warnings, vec!["Part title 'About {{#book_title}}' contains directives, which are only processed with process-part-titles = true".to_string()]);
+    }
+
+    #[test]
+    fn test_urls_for_book_lists_every_chapter() {
+        let mut book = Book::new();
+        let mut intro = Chapter::new("Intro", String::new(), "intro.md", Vec::new());
+        intro.sub_items.push(BookItem::Chapter(Chapter::new("Details", String::new(), "intro/details.md", vec!["Intro".to_string()])));
+        book.push_item(intro);
+        book.push_item(BookItem::Separator);
+        book.push_item(Chapter::new_draft("Someday", Vec::new()));
+        book.push_item(Chapter::new("Outro", String::new(), "outro.md", Vec::new()));
+
+        let subject = PathProcessor::new();
+
+        let urls = subject.urls_for_book(&book, &processor_options("/docs/"));
+
+        assert_eq!(urls, vec![
+            ("Intro".to_string(), "/docs/intro.md".to_string()),
+            ("Details".to_string(), "/docs/intro/details.md".to_string()),
+            ("Outro".to_string(), "/docs/outro.md".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_process_chapter_passes_line_number_anchors_through_verbatim() {
+        let content = "[see line 42]({{#path_for Foo#L42}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "src/foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "[see line 42](/src/foo.md#L42)".to_string());
+    }
+
+    #[test]
+    fn test_profile_summary_lists_slowest_chapters() {
+        let subject = PathProcessor::new();
+
+        let timings = vec![
+            ("Fast".to_string(), Duration::from_millis(1)),
+            ("Slow".to_string(), Duration::from_millis(30)),
+            ("Medium".to_string(), Duration::from_millis(5))
+        ];
+
+        let summary = subject.profile_summary(timings, 2);
+
+        assert!(summary.starts_with("chapter-path processed 3 chapters in 36ms; slowest:\n"));
+        assert!(summary.contains("Slow"));
+        assert!(summary.contains("Medium"));
+        assert!(!summary.contains("Fast"));
+    }
+
+    #[test]
+    fn test_missing_message_default() {
+        let subject = PathProcessor::new();
+
+        let message = subject.missing_message("foo", Some(Path::new("bar.md")), &HashMap::new(), &processor_options("/"));
+
+        assert_eq!(message, "Found request to replace link with 'foo', but no chapter with that name found.");
+    }
+
+    #[test]
+    fn test_missing_message_custom_template() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("installation".to_string(), chapter("installation", "installation.md"));
+        chapter_mapping.insert("usage".to_string(), chapter("usage", "usage.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.missing_message = "{file}: Kapitel '{name}' nicht gefunden. Meinten Sie '{suggestion}'?".to_string();
+
+        let message = subject.missing_message("instalation", Some(Path::new("guide/intro.md")), &chapter_mapping, &options);
+
+        assert_eq!(message, "guide/intro.md: Kapitel 'instalation' nicht gefunden. Meinten Sie 'installation'?");
+    }
+
+    #[test]
+    fn test_suggest_ignores_distant_names() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("installation".to_string(), chapter("installation", "installation.md"));
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.suggest("usage", &chapter_mapping), None);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(crate::edit_distance("kitten", "sitting"), 3);
+        assert_eq!(crate::edit_distance("", "abc"), 3);
+        assert_eq!(crate::edit_distance("same", "same"), 0);
+    }
+
+    #[test]
+    fn test_process_chapter_repeated_references() {
+        let content = "[a]({{#path_for Foo}}) [b]({{#path_for foo}}) [c]({{#path_for Foo}}) [d]({{#path_for Foo#bar}}) [e]({{#path_for Foo}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("foo", "Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "[a](/Foo.md) [b](/Foo.md) [c](/Foo.md) [d](/Foo.md#bar) [e](/Foo.md)".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_located_reports_directive_offset() {
+        let content = "# Title\n\nSee [foo]({{#path_for Foo}}).\n";
+
+        let subject = PathProcessor::new();
+
+        let error = subject.process_chapter_located(content, None, &HashMap::new(), &processor_options("/"), &mut Vec::new()).err().unwrap();
+
+        assert_eq!(error.error, ProcessorError::ChapterNotFound("foo".to_string()));
+        assert_eq!(crate::line_number(content, error.offset), 3);
+    }
+
+    #[test]
+    fn test_process_chapter_empty_argument_uses_default_target() {
+        let content = "[home]({{#path_for}}) [top]({{#path_for #top}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("introduction".to_string(), chapter("introduction", "README.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "[home](/README.md) [top](/README.md#top)".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_default_target_keyword() {
+        let content = "[home]({{#path_for Home}}) [top]({{#path_for home#top}}) [other]({{#path_for Usage}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("introduction".to_string(), chapter("Introduction", "README.md"));
+        chapter_mapping.insert("usage".to_string(), chapter("Usage", "usage.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.default_target_keyword = Some("home".to_string());
+        options.warn_case_mismatch = true;
+
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "[home](/README.md) [top](/README.md#top) [other](/usage.md)".to_string());
+        assert!(warnings.is_empty());
+
+        options.default_target_keyword = None;
+        let error = subject.process_chapter("{{#path_for home}}", &chapter_mapping, &options).unwrap_err();
+
+        assert_eq!(error, ProcessorError::ChapterNotFound("home".to_string()));
+    }
+
+    #[test]
+    fn test_options_default_target_keyword() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            default-target = "Welcome"
+            default-target-keyword = "home"
+            "#).unwrap();
+
+        let subject = PathProcessor::new();
+        let options = subject.options_from_config(&config);
+
+        assert_eq!(options.default_target, Some("Welcome".to_string()));
+        assert_eq!(options.default_target_keyword, Some("home".to_string()));
+        assert_eq!(subject.options_from_config(&Config::default()).default_target_keyword, None);
+    }
+
+    #[test]
+    fn test_process_chapter_missing_default_target() {
+        let subject = PathProcessor::new();
+
+        let error = subject.process_chapter("{{#path_for}}", &HashMap::new(), &processor_options("/")).unwrap_err();
+
+        assert_eq!(error, ProcessorError::DefaultTargetNotFound("Introduction".to_string()));
+    }
+
+    #[test]
+    fn test_process_chapter_warns_on_case_mismatch() {
+        let content = "[a]({{#path_for Getting Started}})\n[b]({{#path_for getting started}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("getting started".to_string(), chapter("Getting Started", "start.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.warn_case_mismatch = true;
+
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "[a](/start.md)\n[b](/start.md)".to_string());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "Reference to 'getting started' differs in case from the chapter name; use 'Getting Started'");
+        assert_eq!(crate::line_number(content, warnings[0].offset), 2);
+    }
+
+    #[test]
+    fn test_process_chapter_ignores_case_mismatch_by_default() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("getting started".to_string(), chapter("Getting Started", "start.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut warnings = Vec::new();
+        subject.process_chapter_located("{{#path_for getting started}}", None, &chapter_mapping, &processor_options("/"), &mut warnings).ok().unwrap();
+
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_directives() {
+        let content = "[a]({{#path_for Foo}}) \\{{#path_for Bar}} [c]({{#path_for Baz#qux}}) {{#path_for}}";
+
+        let directives = crate::parse_directives(content);
+
+        assert_eq!(directives, vec![
+            ParsedDirective { span: 4..21, keyword: "path_for", name: "Foo", anchor: None, escaped: false },
+            ParsedDirective { span: 23..41, keyword: "path_for", name: "Bar", anchor: None, escaped: true },
+            ParsedDirective { span: 46..67, keyword: "path_for", name: "Baz", anchor: Some("qux"), escaped: false },
+            ParsedDirective { span: 69..82, keyword: "path_for", name: "", anchor: None, escaped: false }
+        ]);
+        assert_eq!(&content[directives[1].span.clone()], "\\{{#path_for Bar}}");
+    }
+
+    #[test]
+    fn test_process_chapter_leaves_escaped_directives_as_text() {
+        let content = "Write \\{{#path_for Foo}} to get [this]({{#path_for Foo}}).";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "Foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "Write {{#path_for Foo}} to get [this](/Foo.md).".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_table_safe_escapes_pipes() {
+        let content = "| Operator | Docs |\n|---|---|\n| or | [link]({{#path_for Operators#a|b}}) |";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("operators".to_string(), chapter("Operators", "operators.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.table_safe = true;
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "| Operator | Docs |\n|---|---|\n| or | [link](/operators.md#a%7Cb) |".to_string());
+    }
+
+    #[test]
+    fn test_ordering_warnings_when_links_listed_with_defaults() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+
+            [preprocessor.links]
+        "#).unwrap();
+
+        let warnings = PathProcessor::new().ordering_warnings(&config);
+
+        assert_eq!(warnings, vec!["The links preprocessor is configured to run after chapter-path, so directives it adds (e.g. from included files) won't be processed".to_string()]);
+    }
+
+    #[test]
+    fn test_ordering_warnings_when_defaults_disabled_and_links_listed() {
+        let config = Config::from_str(r#"
+            [build]
+            use-default-preprocessors = false
+
+            [preprocessor.chapter-path]
+
+            [preprocessor.links]
+        "#).unwrap();
+
+        assert_eq!(PathProcessor::new().ordering_warnings(&config).len(), 1);
+    }
+
+    #[test]
+    fn test_ordering_warnings_when_defaults_disabled_and_links_not_listed() {
+        let config = Config::from_str(r#"
+            [build]
+            use-default-preprocessors = false
+
+            [preprocessor.chapter-path]
+        "#).unwrap();
+
+        assert!(PathProcessor::new().ordering_warnings(&config).is_empty());
+    }
+
+    #[test]
+    fn test_ordering_warnings_ignore_before_and_after() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            before = ["links"]
+        "#).unwrap();
+
+        assert!(PathProcessor::new().ordering_warnings(&config).is_empty());
+    }
+
+    #[test]
+    fn test_ordering_warnings_default_config() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            after = ["links"]
+        "#).unwrap();
+
+        assert!(PathProcessor::new().ordering_warnings(&config).is_empty());
+    }
+
+    #[test]
+    fn test_process_chapter_anchor_prefix() {
+        let content = "[a]({{#path_for Foo#usage}}) [b]({{#path_for Foo#L42}}) [c]({{#path_for Foo}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.anchor_prefix = "content-".to_string();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "[a](/foo.md#content-usage) [b](/foo.md#L42) [c](/foo.md)".to_string());
+    }
+
+    #[test]
+    fn test_is_line_anchor() {
+        assert!(crate::is_line_anchor("L42"));
+        assert!(!crate::is_line_anchor("L"));
+        assert!(!crate::is_line_anchor("Lists"));
+        assert!(!crate::is_line_anchor("l42"));
+    }
+
+    #[test]
+    fn test_fix_chapter_rewrites_confident_typos() {
+        let content = "[a]({{#path_for Instalation#linux}})\n[b]({{#path_for Usage}})\n[c]({{#path_for Zzz}})\n\\{{#path_for Instalation}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("installation".to_string(), chapter("Installation", "installation.md"));
+        chapter_mapping.insert("usage".to_string(), chapter("Usage", "usage.md"));
+
+        let subject = PathProcessor::new();
+
+        let (fixed_content, fixes) = subject.fix_chapter(content, &chapter_mapping, &processor_options("/"));
+
+        assert_eq!(fixed_content, "[a]({{#path_for Installation#linux}})\n[b]({{#path_for Usage}})\n[c]({{#path_for Zzz}})\n\\{{#path_for Instalation}}");
+        assert_eq!(fixes, vec![ReferenceFix { line: 1, from: "Instalation".to_string(), to: "Installation".to_string() }]);
+    }
+
+    #[test]
+    fn test_fix_chapter_skips_references_that_resolve() {
+        let content = "[a]({{#path_for home}})\n[b]({{#path_for guide/setup}})\n[c]({{#path_for setup.md}})\n[d]({{#path_for guide/setup.html}})\n[e]({{#path_for @guide/setup}})\n[f]({{#path_for Guide Setpu}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("introduction".to_string(), chapter("Introduction", "README.md"));
+        chapter_mapping.insert("hose".to_string(), chapter("Hose", "hose.md"));
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/setup.md"));
+        chapter_mapping.insert("guide setup".to_string(), chapter("Guide Setup", "setup-guide.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.default_target_keyword = Some("home".to_string());
+        options.resolution_order = vec![Lookup::Name, Lookup::Path];
+
+        let (fixed_content, fixes) = subject.fix_chapter(content, &chapter_mapping, &options);
+
+        assert_eq!(fixed_content, "[a]({{#path_for home}})\n[b]({{#path_for guide/setup}})\n[c]({{#path_for setup.md}})\n[d]({{#path_for guide/setup.html}})\n[e]({{#path_for @guide/setup}})\n[f]({{#path_for Guide Setup}})");
+        assert_eq!(fixes, vec![ReferenceFix { line: 6, from: "Guide Setpu".to_string(), to: "Guide Setup".to_string() }]);
+    }
+
+    #[test]
+    fn test_fix_chapter_skips_ambiguous_typos() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("part 1".to_string(), chapter("Part 1", "part1.md"));
+        chapter_mapping.insert("part 2".to_string(), chapter("Part 2", "part2.md"));
+
+        let subject = PathProcessor::new();
+
+        let (fixed_content, fixes) = subject.fix_chapter("{{#path_for Part 3}}", &chapter_mapping, &processor_options("/"));
+
+        assert_eq!(fixed_content, "{{#path_for Part 3}}");
+        assert!(fixes.is_empty());
+    }
+
+    #[test]
+    fn test_chapter_names_handles_generated_chapters() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Intro", String::new(), "intro.md", Vec::new()));
+        book.push_item(Chapter::new("Generated", "# Generated".to_string(), "./generated/index.md", Vec::new()));
+        book.push_item(Chapter::new("Rooted", "# Rooted".to_string(), "/rooted.md", Vec::new()));
+        book.push_item(Chapter::new("Escaping", "# Escaping".to_string(), "../outside.md", Vec::new()));
+        let mut pathless = Chapter::new_draft("Pathless", Vec::new());
+        pathless.content = "# Pathless".to_string();
+        book.push_item(pathless);
+        book.push_item(Chapter::new_draft("Draft", Vec::new()));
+
+        let subject = PathProcessor::new();
+
+        let mut warnings = Vec::new();
+        let chapter_mapping = subject.collect_chapter_names(&book, &processor_options("/"), &mut warnings).unwrap();
+
+        assert_eq!(chapter_mapping.get("generated"), Some(&chapter("Generated", "generated/index.md")));
+        assert_eq!(chapter_mapping.get("rooted"), Some(&chapter("Rooted", "rooted.md")));
+        assert_eq!(chapter_mapping.get("escaping"), None);
+        assert_eq!(chapter_mapping.len(), 3);
+        assert_eq!(warnings, vec![
+            "Chapter Escaping at ../outside.md points outside the book, so it can't be linked to".to_string(),
+            "Chapter Pathless has no path, so it can't be linked to".to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_process_chapter_short_path_for() {
+        let content = "[share](https://example.com{{#short_path_for Getting Started}}) [full]({{#path_for Getting Started}}) [deep]({{#short_path_for Getting Started#install}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("getting started".to_string(), chapter("Getting Started", "guide/start.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.short_url_template = Some("/s/{slug}".to_string());
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "[share](https://example.com/s/getting-started) [full](/guide/start.md) [deep](/s/getting-started#install)".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_short_path_for_url_options() {
+        let content = "{{#short_path_for Café Setup#install}} {{#short_path_for Café Setup#L42}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("café setup".to_string(), chapter("Café Setup", "guide/setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.short_url_template = Some("/s/{slug}?from=a|b".to_string());
+        options.anchor_prefix = "content-".to_string();
+        options.encoding = UrlEncoding::Strict;
+        options.table_safe = true;
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).unwrap();
+
+        assert_eq!(received_chapter, "/s/caf%C3%A9-setup?from=a%7Cb#content-install /s/caf%C3%A9-setup?from=a%7Cb#L42".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_short_path_for_without_template() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let error = subject.process_chapter("{{#short_path_for Foo}}", &chapter_mapping, &processor_options("/")).unwrap_err();
+
+        assert_eq!(error, ProcessorError::ShortUrlTemplateMissing("Foo".to_string()));
+    }
+
+    #[test]
+    fn test_process_part_title_warns_about_directives() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut warnings = Vec::new();
+        let processed = subject.process_part_title("Part 1 ({{#chapter_count}} at {{#path_for Foo}})", &chapter_mapping, &processor_options("/"), &mut warnings).ok().unwrap();
+
+        assert_eq!(processed, None);
+        assert_eq!(warnings, vec!["Part title 'Part 1 ({{#chapter_count}} at {{#path_for Foo}})' contains directives, which are only processed with process-part-titles = true".to_string()]);
+    }
+
+    #[test]
+    fn test_process_part_title_processes_when_enabled() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.process_part_titles = true;
+
+        let mut warnings = Vec::new();
+        let processed = subject.process_part_title("See {{#path_for Foo}}", &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(processed, Some("See /foo.md".to_string()));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_process_part_title_without_directives() {
+        let subject = PathProcessor::new();
+
+        let mut warnings = Vec::new();
+        let processed = subject.process_part_title("Reference", &HashMap::new(), &processor_options("/"), &mut warnings).ok().unwrap();
+
+        assert_eq!(processed, None);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_options_default_to_root_relative_links() {
+        let options = PathProcessor::new().options_from_config(&Config::default());
+
+        assert_eq!(options.site_path, "/");
+        assert!(!options.relative_links);
+    }
+
+    #[test]
+    fn test_options_default_site_path() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            default-site-path = "/docs"
+        "#).unwrap();
+
+        let options = PathProcessor::new().options_from_config(&config);
+
+        assert_eq!(options.site_path, "/docs/");
+        assert!(!options.relative_links);
+    }
+
+    #[test]
+    fn test_options_default_mode_relative() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            default-mode = "relative"
+        "#).unwrap();
+
+        assert!(PathProcessor::new().options_from_config(&config).relative_links);
+    }
+
+    #[test]
+    fn test_options_site_url_overrides_default_mode() {
+        let config = Config::from_str(r#"
+            [output.html]
+            site-url = "/book/"
+
+            [preprocessor.chapter-path]
+            default-mode = "relative"
+        "#).unwrap();
+
+        let options = PathProcessor::new().options_from_config(&config);
+
+        assert_eq!(options.site_path, "/book/");
+        assert!(!options.relative_links);
+    }
+
+    #[test]
+    fn test_process_chapter_relative_links() {
+        let content = "[a]({{#path_for Setup}}) [b]({{#path_for Intro#top}}) [c]({{#path_for Sibling}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/advanced/setup.md"));
+        chapter_mapping.insert("intro".to_string(), chapter("Intro", "intro.md"));
+        chapter_mapping.insert("sibling".to_string(), chapter("Sibling", "guide/basics/sibling.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.relative_links = true;
+
+        let received_chapter = subject.process_chapter_located(content, Some(Path::new("guide/basics/current.md")), &chapter_mapping, &options, &mut Vec::new()).ok().unwrap();
+
+        assert_eq!(received_chapter, "[a](../advanced/setup.md) [b](../../intro.md#top) [c](sibling.md)".to_string());
+    }
+
+    #[test]
+    fn test_options_diagnostics_file_relative_to_root() {
+        let config = Config::from_str(r#"
+            [build]
+            build-dir = "out"
+
+            [preprocessor.chapter-path]
+            diagnostics-file = "chapter-path.log"
+        "#).unwrap();
+
+        let options = PathProcessor::new().options_for_root(&config, Path::new("/book"));
+
+        assert_eq!(options.diagnostics_file, Some(PathBuf::from("/book/chapter-path.log")));
+    }
+
+    #[test]
+    fn test_diagnostics_file_survives_build() {
+        let dir = fixture_book_dir("diagnostics-file = \"chapter-path.log\"\nwarn-case-mismatch = true\n");
+
+        build_fixture_book(dir.path());
+
+        assert!(dir.path().join("book").join("intro.html").exists());
+        assert_eq!(std::fs::read_to_string(dir.path().join("chapter-path.log")).unwrap(),
+            "Warning: src/intro.md:3: Reference to 'setup' differs in case from the chapter name; use 'Setup'\n");
+    }
+
+    #[test]
+    fn test_process_book_writes_profile_summary_to_diagnostics_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            profile = true
+            diagnostics-file = "chapter-path.log"
+            "#).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "Intro".to_string(), "intro.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        subject.process_book(&config, dir.path(), book).unwrap();
+
+        let log = std::fs::read_to_string(dir.path().join("chapter-path.log")).unwrap();
+        assert!(log.starts_with("Note: chapter-path processed 1 chapters in "), "{}", log);
+        assert!(log.contains("\tIntro\n"), "{}", log);
+    }
+
+    #[test]
+    fn test_report_writes_diagnostics_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("book").join("chapter-path.log");
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.output_format = OutputFormat::Json;
+        options.diagnostics_file = Some(file.clone());
+
+        subject.start_diagnostics_file(&options);
+        subject.report(Diagnostic::warning("First".to_string()), &options);
+        subject.report(Diagnostic::error("Second".to_string()).at(PathBuf::from("src/foo.md"), 2), &options);
+
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "{\"level\":\"warning\",\"message\":\"First\"}\n{\"file\":\"src/foo.md\",\"level\":\"error\",\"line\":2,\"message\":\"Second\"}\n"
+        );
+
+        subject.start_diagnostics_file(&options);
+
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "");
+    }
+
+    #[test]
+    fn test_process_chapter_only_changes_directive_spans() {
+        // Text around directives, including whitespace, unicode and lookalikes that aren't directives.
+        let fillers = [
+            "", " ", "  \t", "\n", "\r\n", "\n\n  ", "plain text", "ünïcödé ✓", "[link](", ")", "{", "}", "{{", "}}",
+            "{{#include foo.md}}", "`code`", "# Heading\n", "| a | b |", "<!-- comment -->", "#anchor"
+        ];
+        let directives = [
+            ("{{#path_for Foo}}", "/foo.md"),
+            ("{{#path_for foo#bar}}", "/foo.md#bar"),
+            ("{{#path_for Some Chapter}}", "/a/some_chapter.md"),
+            ("{{#path_for some chapter#L3}}", "/a/some_chapter.md#L3")
+        ];
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "foo.md"));
+        chapter_mapping.insert("some chapter".to_string(), chapter("Some Chapter", "a/some_chapter.md"));
+
+        let subject = PathProcessor::new();
+        let options = processor_options("/");
+
+        // Small fixed-seed LCG, so failures are reproducible without a property testing dependency.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: usize| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) as usize) % bound
+        };
+
+        for _ in 0..500 {
+            let mut content = String::new();
+            let mut expected = String::new();
+            for _ in 0..next(8) {
+                let filler = fillers[next(fillers.len())];
+                content.push_str(filler);
+                expected.push_str(filler);
+                if next(2) == 0 {
+                    let (directive, url) = directives[next(directives.len())];
+                    content.push_str(directive);
+                    expected.push_str(url);
+                }
+            }
+
+            let received_chapter = subject.process_chapter(&content, &chapter_mapping, &options).ok().unwrap();
+
+            assert_eq!(received_chapter, expected, "input: {:?}", content);
+        }
+    }
+
+    #[test]
+    fn test_process_chapter_rooted_path_hint() {
+        let content = "{{#path_for @/setup}} {{#path_for @/guide/setup.md}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+        chapter_mapping.insert("guide setup".to_string(), chapter("Guide Setup", "guide/setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).ok().unwrap();
+
+        assert_eq!(received_chapter, "/setup.md /guide/setup.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_zola_compat_links() {
+        let content = "See [setup](@/guide/setup.md#install) and [intro](@/intro.md), or {{#path_for Intro}}. Not a link: @/intro.md";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("intro".to_string(), chapter("Intro", "intro.md"));
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.compat = Some(CompatSyntax::Zola);
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "See [setup](/guide/setup.md#install) and [intro](/intro.md), or /intro.md. Not a link: @/intro.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_zola_compat_links_disabled() {
+        let content = "[intro](@/intro.md)";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("intro".to_string(), chapter("Intro", "intro.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).ok().unwrap();
+
+        assert_eq!(received_chapter, content.to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_zola_compat_link_not_found() {
+        let content = "[missing](@/missing.md)";
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.compat = Some(CompatSyntax::Zola);
+
+        let received_error = subject.process_chapter(content, &HashMap::new(), &options).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::ChapterNotFound("@/missing.md".to_string()));
+    }
+
+    #[test]
+    fn test_process_chapter_zola_compat_link_with_several_anchors() {
+        let content = "[x](@/a.md#b#c) [a](@/a.md#b)";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("a".to_string(), chapter("A", "a.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.compat = Some(CompatSyntax::Zola);
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "[x](@/a.md#b#c) [a](/a.md#b)".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_backslash_path_hint_error() {
+        let content = "{{#path_for @guide\\setup}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.backslash_check = CheckLevel::Error;
+
+        let received_error = subject.process_chapter(content, &chapter_mapping, &options).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::BackslashInPathHint("@guide\\setup".to_string(), "@guide/setup".to_string()));
+        assert_eq!(received_error.to_string(), "Path hint '@guide\\setup' uses backslashes as separators; use '@guide/setup' instead.");
+    }
+
+    #[test]
+    fn test_process_chapter_backslash_path_hint_warning() {
+        let content = "Intro\n{{#path_for @guide\\setup}} {{#path_for Back\\slash}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/setup.md"));
+        chapter_mapping.insert("back\\slash".to_string(), chapter("Back\\slash", "back_slash.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.backslash_check = CheckLevel::Warn;
+
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "Intro\n/guide/setup.md /back_slash.md".to_string());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "Path hint '@guide\\setup' uses backslashes as separators; use '@guide/setup' instead.");
+        assert_eq!(warnings[0].offset, 6);
+    }
+
+    #[test]
+    fn test_chapter_names_with_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.json");
+        std::fs::write(&manifest, r#"{"Foo": "https://example.com/foo", "API Reference": "https://docs.example.com/api"}"#).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Foo", "".to_string(), "foo.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Bar", "".to_string(), "bar.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.manifest = Some(manifest);
+
+        let chapter_mapping = subject.collect_chapter_names(&book, &options, &mut Vec::new()).unwrap();
+
+        let content = "{{#path_for Foo#usage}} {{#path_for api reference}} {{#path_for Bar}} {{#path_for @foo}}";
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "https://example.com/foo#usage https://docs.example.com/api /bar.md https://example.com/foo".to_string());
+    }
+
+    #[test]
+    fn test_chapter_names_with_toml_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.toml");
+        std::fs::write(&manifest, "\"Style Guide\" = \"/handbook/style.html\"\n").unwrap();
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.manifest = Some(manifest);
+
+        let chapter_mapping = subject.collect_chapter_names(&Book::new(), &options, &mut Vec::new()).unwrap();
+
+        assert_eq!(chapter_mapping.get("style guide").unwrap().url, Some("/handbook/style.html".to_string()));
+    }
+
+    #[test]
+    fn test_chapter_names_with_invalid_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.json");
+        std::fs::write(&manifest, r#"["Foo"]"#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.manifest = Some(manifest.clone());
+
+        let received_error = subject.collect_chapter_names(&Book::new(), &options, &mut Vec::new()).err().unwrap();
+
+        assert!(matches!(received_error, ProcessorError::InvalidManifest(path, _) if path == manifest));
+    }
+
+    #[test]
+    fn test_process_book_disabled_leaves_diagnostics_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("diagnostics.log"), "previous run\n").unwrap();
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            diagnostics-file = "diagnostics.log"
+            "#).unwrap();
+
+        let content = "[Foo]({{#path_for Missing}})";
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Foo", content.to_string(), "foo.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let processed_book = subject.process_book_unless_disabled(&config, dir.path(), book, Some("1")).unwrap();
+
+        match processed_book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => assert_eq!(chapter.content, content),
+            _ => panic!("Expected a chapter")
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("diagnostics.log")).unwrap(), "previous run\n");
+    }
+
+    #[test]
+    fn test_disabled_by_env_var_value() {
+        let subject = PathProcessor::new();
+
+        assert!(subject.disabled(Some("1")));
+        assert!(subject.disabled(Some("true")));
+        assert!(!subject.disabled(Some("0")));
+        assert!(!subject.disabled(Some("false")));
+        assert!(!subject.disabled(Some("")));
+        assert!(!subject.disabled(None));
+    }
+
+    #[test]
+    fn test_process_book_backlinks() {
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "See {{#path_for Setup}}.\n\n{{#backlinks_for Intro}}".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "Back to {{#path_for Intro}}, or [setup]({{#path_for Setup}}).\n\nLinked from:\n{{#backlinks_for Setup}}".to_string(), "guide/setup.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Usage", "After {{#path_for setup}} and {{#path_for Setup#install}}.\n{{#backlinks_for Usage}}".to_string(), "guide/usage.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let processed_book = subject.process_book(&Config::default(), Path::new("."), book).unwrap();
+
+        let contents: Vec<String> = processed_book.iter().filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(contents, vec![
+            "See /guide/setup.md.\n\n- [Setup](/guide/setup.md)".to_string(),
+            "Back to /intro.md, or [setup](/guide/setup.md).\n\nLinked from:\n- [Intro](/intro.md)\n- [Usage](/guide/usage.md)".to_string(),
+            "After /guide/setup.md and /guide/setup.md#install.\n".to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_process_book_backlinks_with_backslash_path_hint() {
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "See {{#path_for @guide\\setup}}.".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "Linked from:\n{{#backlinks_for Setup}}".to_string(), "guide/setup.md", Vec::new())));
+
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            backslash-check = "warn"
+            "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        let processed_book = subject.process_book(&config, Path::new("."), book).unwrap();
+
+        let contents: Vec<String> = processed_book.iter().filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(contents, vec![
+            "See /guide/setup.md.".to_string(),
+            "Linked from:\n- [Intro](/intro.md)".to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_strip_number_prefix() {
+        let content = "{{#path_for @setup}} {{#path_for @guide/usage}} {{#path_for @03-faq}} {{#path_for @/intro}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("introduction".to_string(), chapter("Introduction", "01-intro.md"));
+        chapter_mapping.insert("getting set up".to_string(), chapter("Getting Set Up", "02-setup.md"));
+        chapter_mapping.insert("usage".to_string(), chapter("Usage", "04_guide/01_usage.md"));
+        chapter_mapping.insert("faq".to_string(), chapter("FAQ", "03-faq.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.number_prefix = Some(Regex::new(crate::DEFAULT_NUMBER_PREFIX).unwrap());
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "/02-setup.md /04_guide/01_usage.md /03-faq.md /01-intro.md".to_string());
+
+        let received_error = subject.process_chapter("{{#path_for setup}}", &chapter_mapping, &options).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::ChapterNotFound("setup".to_string()));
+    }
+
+    #[test]
+    fn test_process_chapter_path_hint_keeps_number_prefix_by_default() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("getting set up".to_string(), chapter("Getting Set Up", "02-setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_error = subject.process_chapter("{{#path_for @setup}}", &chapter_mapping, &processor_options("/")).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::ChapterNotFound("@setup".to_string()));
+    }
+
+    #[test]
+    fn test_options_strip_number_prefix() {
+        let enabled = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strip-number-prefix = true
+        "#).unwrap();
+        let custom = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strip-number-prefix = '^\d+\.'
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.options_from_config(&enabled).number_prefix.unwrap().as_str(), r"^\d+[-_]");
+        assert_eq!(subject.options_from_config(&custom).number_prefix.unwrap().as_str(), r"^\d+\.");
+        assert!(subject.options_from_config(&Config::default()).number_prefix.is_none());
+    }
+
+    #[test]
+    fn test_format_list_styles() {
+        let items = vec!["[Intro](/intro.md)".to_string(), "[Usage](/usage.md)".to_string()];
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        assert_eq!(subject.format_list(&items, &options), Some("- [Intro](/intro.md)\n- [Usage](/usage.md)".to_string()));
+
+        options.list_style = ListStyle::Loose;
+        assert_eq!(subject.format_list(&items, &options), Some("- [Intro](/intro.md)\n\n- [Usage](/usage.md)".to_string()));
+
+        options.list_style = ListStyle::Inline;
+        assert_eq!(subject.format_list(&items, &options), Some("[Intro](/intro.md), [Usage](/usage.md)".to_string()));
+    }
+
+    #[test]
+    fn test_process_chapter_backlinks_empty_result() {
+        let content = "Linked from: {{#backlinks_for Setup}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        assert_eq!(subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap(), "Linked from: ".to_string());
+
+        options.empty_result = EmptyResult::Placeholder("(no pages)".to_string());
+        assert_eq!(subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap(), "Linked from: (no pages)".to_string());
+
+        options.empty_result = EmptyResult::Error;
+        let received_error = subject.process_chapter(content, &chapter_mapping, &options).err().unwrap();
+        assert_eq!(received_error, ProcessorError::EmptyResult("backlinks_for".to_string(), "Setup".to_string()));
+
+        options.backlinks.insert("setup".to_string(), vec![chapter("Intro", "intro.md")]);
+        assert_eq!(subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap(), "Linked from: - [Intro](/intro.md)".to_string());
+    }
+
+    #[test]
+    fn test_options_empty_result() {
+        let error = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            empty-result = "error"
+        "#).unwrap();
+        let placeholder = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            empty-result = "placeholder:_None yet._"
+        "#).unwrap();
+        let unknown = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            empty-result = "nothing"
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.options_from_config(&error).empty_result, EmptyResult::Error);
+        assert_eq!(subject.options_from_config(&placeholder).empty_result, EmptyResult::Placeholder("_None yet._".to_string()));
+        assert_eq!(subject.options_from_config(&unknown).empty_result, EmptyResult::Empty);
+        assert_eq!(subject.options_from_config(&Config::default()).empty_result, EmptyResult::Empty);
+    }
+
+    #[test]
+    fn test_process_chapter_backlinks_loose_list() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.list_style = ListStyle::Loose;
+        options.backlinks.insert("setup".to_string(), vec![chapter("Intro", "intro.md"), chapter("Usage", "guide/usage.md")]);
+
+        let received_chapter = subject.process_chapter("{{#backlinks_for Setup}}", &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "- [Intro](/intro.md)\n\n- [Usage](/guide/usage.md)".to_string());
+    }
+
+    #[test]
+    fn test_process_book_custom_directive() {
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "Start with {{#link_to getting started#install}}.".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Getting Started", "Back to {{#link_to intro}} from {{#path_for Intro}}.".to_string(), "guide/start.md", Vec::new())));
+
+        let mut subject = PathProcessor::new();
+        subject.register_directive("link_to", |directive, context| {
+            let reference = match directive.anchor {
+                Some(anchor) => format!("{}#{}", directive.name, anchor),
+                None => directive.name.to_string()
+            };
+            Ok(format!("[{}]({})", context.chapter_name(&reference)?, context.chapter_url(&reference)?))
+        }).unwrap();
+
+        let processed_book = subject.process_book(&Config::default(), Path::new("."), book).unwrap();
+
+        let contents: Vec<String> = processed_book.iter().filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(contents, vec![
+            "Start with [Getting Started](/guide/start.md#install).".to_string(),
+            "Back to [Intro](/intro.md) from /intro.md.".to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_register_directive_builtin_keyword() {
+        let mut subject = PathProcessor::new();
+
+        let received_error = subject.register_directive("path_for", |_, _| Ok("overridden".to_string())).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::BuiltinKeyword("path_for".to_string()));
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("intro".to_string(), chapter("Intro", "intro.md"));
+        let received_chapter = subject.process_chapter("{{#path_for Intro}}", &chapter_mapping, &processor_options("/")).unwrap();
+        assert_eq!(received_chapter, "/intro.md".to_string());
+    }
+
+    #[test]
+    fn test_register_directive_invalid_keyword() {
+        let mut subject = PathProcessor::new();
+
+        for keyword in &["", "link to", "link.*", "link}}", "lïnk"] {
+            let received_error = subject.register_directive(keyword, |_, _| Ok("hijacked".to_string())).err().unwrap();
+
+            assert_eq!(received_error, ProcessorError::InvalidKeyword(keyword.to_string()));
+        }
+        assert!(subject.register_directive("link_to2", |_, _| Ok(String::new())).is_ok());
+
+        let received_chapter = subject.process_chapter("{{#}} {{# b}}", &HashMap::new(), &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "{{#}} {{# b}}".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_custom_directive_not_memoized() {
+        let counter = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut subject = PathProcessor::new();
+        let handler_counter = counter.clone();
+        subject.register_directive("footnote", move |_, _| {
+            handler_counter.set(handler_counter.get() + 1);
+            Ok(format!("[{}]", handler_counter.get()))
+        }).unwrap();
+
+        let received_chapter = subject.process_chapter("{{#footnote a}} {{#footnote a}}", &HashMap::new(), &processor_options("/")).unwrap();
+
+        assert_eq!(received_chapter, "[1] [2]".to_string());
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_process_chapter_custom_directive_error() {
+        let mut subject = PathProcessor::new();
+        subject.register_directive("link_to", |directive, context| context.chapter_url(directive.name)).unwrap();
+
+        let received_error = subject.process_chapter("{{#link_to Missing}}", &HashMap::new(), &processor_options("/")).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::ChapterNotFound("missing".to_string()));
+    }
+
+    #[test]
+    fn test_heading_ids() {
+        let content = "# Getting Started\n\n## Install `foo`\n\n```\n# not a heading\n```\n\n## Usage\n\nUsage\n---\n";
+
+        let subject = PathProcessor::new();
+
+        let mut received_ids: Vec<(String, usize)> = subject.heading_ids(content, &processor_options("/")).into_iter().collect();
+        received_ids.sort();
+
+        assert_eq!(received_ids, vec![
+            ("getting-started".to_string(), 1),
+            ("install-foo".to_string(), 1),
+            ("usage".to_string(), 2),
+            ("usage-1".to_string(), 1)
+        ]);
+    }
+
+    #[test]
+    fn test_process_chapter_validate_anchors() {
+        let content = "{{#path_for Setup#install}} {{#path_for Setup#L12}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.validate_anchors = true;
+        options.anchors.insert(PathBuf::from("setup.md"), subject.heading_ids("# Setup\n\n## Install\n", &processor_options("/")));
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "/setup.md#install /setup.md#L12".to_string());
+
+        let received_error = subject.process_chapter("{{#path_for Setup#instal}}", &chapter_mapping, &options).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::AnchorNotFound("Setup".to_string(), "instal".to_string()));
+    }
+
+    #[test]
+    fn test_validate_anchors_from_included_content() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("book.toml"), "[book]\ntitle = \"Fixture\"\n\n[preprocessor.chapter-path]\nvalidate-anchors = true\n").unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src").join("SUMMARY.md"), "- [Intro](intro.md)\n- [Setup](setup.md)\n").unwrap();
+        std::fs::write(dir.path().join("src").join("intro.md"), "# Intro\n\nSee [installing]({{#path_for Setup#installing}}).\n").unwrap();
+        std::fs::write(dir.path().join("src").join("setup.md"), "# Setup\n\n{{#include install.md}}\n").unwrap();
+        std::fs::write(dir.path().join("src").join("install.md"), "## Installing\n\nRun the installer.\n").unwrap();
+
+        let md = mdbook::MDBook::load(dir.path()).unwrap();
+        let ctx: PreprocessorContext = serde_json::from_value(serde_json::json!({
+            "root": dir.path(),
+            "config": md.config,
+            "renderer": "html",
+            "mdbook_version": mdbook::MDBOOK_VERSION
+        })).unwrap();
+
+        let book = LinkPreprocessor::new().run(&ctx, md.book.clone()).unwrap();
+        let processed_book = PathProcessor::new().run(&ctx, book).unwrap();
+
+        let intro = processed_book.iter().find_map(|item| match item {
+            BookItem::Chapter(chapter) if chapter.name == "Intro" => Some(chapter.content.clone()),
+            _ => None
+        }).unwrap();
+        assert_eq!(intro, "# Intro\n\nSee [installing](/setup.md#installing).\n");
+    }
+
+    #[test]
+    fn test_process_chapter_missing_chapter_warning() {
+        let content = "[a]({{#path_for Missing}}) [b]({{#path_for Foo}}) [c]({{#path_for missing}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("foo".to_string(), chapter("Foo", "foo.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.missing_chapter = MissingChapter::Warn;
+
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "[a]({{#path_for Missing}}) [b](/foo.md) [c]({{#path_for missing}})".to_string());
+        assert_eq!(warnings.iter().map(|warning| warning.missing.clone()).collect::<Vec<Option<String>>>(), vec![Some("missing".to_string()), Some("missing".to_string())]);
+    }
+
+    #[test]
+    fn test_process_book_deduplicates_missing_chapter_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("chapter-path.log");
+        let config = Config::from_str(&format!(r#"
+            [preprocessor.chapter-path]
+            missing-chapter = "warn"
+            diagnostics-file = {:?}
+        "#, log.to_str().unwrap())).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "Intro\n{{#path_for Setup}} {{#path_for setup}}".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Usage", "{{#path_for Setup}} {{#path_for Other}}".to_string(), "usage.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        subject.process_book(&config, dir.path(), book).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), concat!(
+            "Warning: src/intro.md:2: Found request to replace link with 'setup', but no chapter with that name found. (3 references)\n",
+            "Warning: src/usage.md:1: Found request to replace link with 'other', but no chapter with that name found.\n"
+        ));
+    }
+
+    #[test]
+    fn test_process_chapter_relative_link_escaping_root() {
+        let content = "{{#path_for Intro}}";
+        let current_path = Some(Path::new("../outside/page.md"));
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("intro".to_string(), chapter("Intro", "intro.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.relative_links = true;
+
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, current_path, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "../../intro.md".to_string());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "Link to 'Intro' resolves to '../../intro.md', which leads outside the site root.");
+
+        options.escape_check = CheckLevel::Error;
+        let received_error = subject.process_chapter_located(content, current_path, &chapter_mapping, &options, &mut Vec::new()).err().unwrap();
+
+        assert_eq!(received_error.error, ProcessorError::EscapesRoot("Intro".to_string(), "../../intro.md".to_string()));
+
+        options.escape_check = CheckLevel::Off;
+        let mut warnings = Vec::new();
+        subject.process_chapter_located(content, current_path, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert!(warnings.is_empty());
+
+        options.escape_check = CheckLevel::Error;
+        let received_chapter = subject.process_chapter_located(content, Some(Path::new("guide/deep/page.md")), &chapter_mapping, &options, &mut Vec::new()).ok().unwrap();
+
+        assert_eq!(received_chapter, "../../intro.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_relative_link_from_non_canonical_path() {
+        let content = "{{#path_for Setup}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.relative_links = true;
+
+        let received_chapter = subject.process_chapter_located(content, Some(Path::new("./guide/../intro.md")), &chapter_mapping, &options, &mut Vec::new()).ok().unwrap();
+
+        assert_eq!(received_chapter, "guide/setup.md".to_string());
+
+        let received_chapter = subject.process_chapter_located(content, Some(Path::new("guide//basics/./page.md")), &chapter_mapping, &options, &mut Vec::new()).ok().unwrap();
+
+        assert_eq!(received_chapter, "../setup.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_mtime_for() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src").join("guide")).unwrap();
+        let file = dir.path().join("src").join("guide").join("setup.md");
+        std::fs::write(&file, "# Setup\n").unwrap();
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+
+        let content = "Updated {{#mtime_for Setup}} ({{#mtime_for Missing File}})";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "guide/setup.md"));
+        chapter_mapping.insert("missing file".to_string(), chapter("Missing File", "missing.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.book_root = dir.path().to_path_buf();
+
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "Updated 2020-09-13 ()".to_string());
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.starts_with("Unable to read the modification time of "));
+
+        options.mtime_format = "%d %B %Y, %H:%M".to_string();
+        let received_chapter = subject.process_chapter("{{#mtime_for Setup}}", &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "13 September 2020, 12:26".to_string());
+    }
+
+    #[test]
+    fn test_options_invalid_mtime_format() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            mtime-format = "%Y-%"
+        "#).unwrap();
+
+        assert_eq!(PathProcessor::new().options_from_config(&config).mtime_format, "%Y-%m-%d");
+    }
+
+    #[test]
+    fn test_process_book_report_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("chapter-path.log");
+        let config = Config::from_str(&format!(r#"
+            [preprocessor.chapter-path]
+            report-only = true
+            warn-case-mismatch = true
+            diagnostics-file = {:?}
+        "#, log.to_str().unwrap())).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "{{#path_for Setup}} {{#path_for setup}}".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "{{#path_for Missing}}".to_string(), "setup.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Usage", "{{#path_for Other}}".to_string(), "usage.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let processed_book = subject.process_book(&config, dir.path(), book.clone()).unwrap();
+
+        assert_eq!(processed_book, book);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), concat!(
+            "Warning: src/intro.md:1: Reference to 'setup' differs in case from the chapter name; use 'Setup'\n",
+            "Error: src/setup.md:1: Found request to replace link with 'missing', but no chapter with that name found.\n",
+            "Error: src/usage.md:1: Found request to replace link with 'other', but no chapter with that name found.\n"
+        ));
+    }
+
+    #[test]
+    fn test_process_chapter_anchor_with_braces() {
+        let content = "[a]({{#path_for Setup#fn-{}}}) [b]({{#path_for Setup#{x}-y}}) {{#path_for Setup}}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).ok().unwrap();
+
+        assert_eq!(received_chapter, "[a](/setup.md#fn-{}) [b](/setup.md#{x}-y) /setup.md}".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_unbalanced_braces() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        for content in &["{{#path_for Setup#a{b}}", "{{#path_for Set}up}}", "ok {{#path_for Setup}} then {{#path_for Setup#{{x}}}}"] {
+            let received_error = subject.process_chapter(content, &chapter_mapping, &processor_options("/")).err().unwrap();
+
+            assert!(matches!(received_error, ProcessorError::UnbalancedBraces(_)), "{:?} for {}", received_error, content);
+        }
+
+        let received_error = subject.process_chapter("{{#path_for Setup#a{b}}", &chapter_mapping, &processor_options("/")).err().unwrap();
+
+        assert_eq!(received_error.to_string(), "Directive '{{#path_for Setup#a{b}}' has unbalanced braces; arguments may only contain matched pairs like '{}'.");
+
+        let received_chapter = subject.process_chapter("\\{{#path_for Setup#a{b}}", &chapter_mapping, &processor_options("/")).ok().unwrap();
+
+        assert_eq!(received_chapter, "\\{{#path_for Setup#a{b}}".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_unbalanced_braces_reports_only_the_malformed_directive() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("intro".to_string(), chapter("Intro", "intro.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_error = subject.process_chapter("{{#path_for }} then {{#path_for Intro}}", &chapter_mapping, &processor_options("/")).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::UnbalancedBraces("{{#path_for }}".to_string()));
+
+        let received_error = subject.process_chapter("{{#path_for Name}` typo; real: {{#path_for Intro}}", &chapter_mapping, &processor_options("/")).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::UnbalancedBraces("{{#path_for Name}` typo; real:".to_string()));
+
+        let received_chapter = subject.process_chapter("{{#path_formatted}} {{#path_for Intro}}", &chapter_mapping, &processor_options("/")).ok().unwrap();
+
+        assert_eq!(received_chapter, "{{#path_formatted}} /intro.md".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_ambiguous_anchor() {
+        let content = "{{#path_for Setup#options}} {{#path_for Setup#options-1}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.validate_anchors = true;
+        options.anchors.insert(PathBuf::from("setup.md"), subject.heading_ids("# Setup\n\n## Linux\n\n### Options\n\n## macOS\n\n### Options\n", &processor_options("/")));
+
+        options.ambiguous_anchor = CheckLevel::Off;
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "/setup.md#options /setup.md#options-1".to_string());
+        assert!(warnings.is_empty());
+
+        options.ambiguous_anchor = CheckLevel::Warn;
+        let mut warnings = Vec::new();
+        let received_chapter = subject.process_chapter_located(content, None, &chapter_mapping, &options, &mut warnings).ok().unwrap();
+
+        assert_eq!(received_chapter, "/setup.md#options /setup.md#options-1".to_string());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "Anchor 'Setup#options' matches 2 headings with the same id. Linking to the first one.");
+
+        options.ambiguous_anchor = CheckLevel::Error;
+        let received_error = subject.process_chapter(content, &chapter_mapping, &options).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::AmbiguousAnchor("Setup".to_string(), "options".to_string(), 2));
+    }
+
+    #[test]
+    fn test_options_ambiguous_anchor_default() {
+        let strict = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strict = true
+        "#).unwrap();
+        let configured = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strict = true
+            ambiguous-anchor = "first"
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.options_from_config(&Config::default()).ambiguous_anchor, CheckLevel::Warn);
+        assert_eq!(subject.options_from_config(&strict).ambiguous_anchor, CheckLevel::Error);
+        assert_eq!(subject.options_from_config(&configured).ambiguous_anchor, CheckLevel::Off);
+    }
+
+    #[test]
+    fn test_process_book_emit_graph() {
+        let dir = tempfile::tempdir().unwrap();
+        let graph = dir.path().join("graph.json");
+        let config = Config::from_str(&format!(r#"
+            [preprocessor.chapter-path]
+            emit-graph = {:?}
+        "#, graph.to_str().unwrap())).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "{{#path_for Setup}} {{#path_for setup#install}} {{#path_for Intro}}".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "{{#path_for Intro}}".to_string(), "guide/setup.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Orphan", "".to_string(), "orphan.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        subject.process_book(&config, dir.path(), book.clone()).unwrap();
+
+        let received_graph: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&graph).unwrap()).unwrap();
+        assert_eq!(received_graph, serde_json::json!({
+            "nodes": [
+                { "name": "Intro", "path": "intro.md" },
+                { "name": "Setup", "path": "guide/setup.md" },
+                { "name": "Orphan", "path": "orphan.md" }
+            ],
+            "edges": [
+                { "from": "intro.md", "to": "guide/setup.md", "count": 2 },
+                { "from": "guide/setup.md", "to": "intro.md", "count": 1 }
+            ]
+        }));
+
+        let references = subject.collect_references(&book, &subject.chapter_names(&book, &processor_options("/")).unwrap(), &processor_options("/"));
+        assert_eq!(subject.reference_graph(&book, &references, Path::new("graph.dot")), concat!(
+            "digraph chapters {\n",
+            "    \"intro.md\" [label=\"Intro\"];\n",
+            "    \"guide/setup.md\" [label=\"Setup\"];\n",
+            "    \"orphan.md\" [label=\"Orphan\"];\n",
+            "    \"intro.md\" -> \"guide/setup.md\" [label=\"2\"];\n",
+            "    \"guide/setup.md\" -> \"intro.md\" [label=\"1\"];\n",
+            "}\n"
+        ));
+    }
+
+    #[test]
+    fn test_process_book_lenient_in_drafts() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("chapter-path.log");
+        let config = Config::from_str(&format!(r#"
+            [preprocessor.chapter-path]
+            strict = true
+            lenient-in-drafts = "^drafts/"
+            diagnostics-file = {:?}
+        "#, log.to_str().unwrap())).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "{{#path_for Setup}}".to_string(), "intro.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "".to_string(), "setup.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Ideas", "{{#path_for Setup}} {{#path_for Missing}}".to_string(), "drafts/ideas.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let processed_book = subject.process_book(&config, dir.path(), book.clone()).unwrap();
+
+        let contents: Vec<String> = processed_book.iter().filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(contents, vec!["/setup.md", "", "{{#path_for Setup}} {{#path_for Missing}}"]);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(),
+            "Warning: src/drafts/ideas.md:1: Found request to replace link with 'missing', but no chapter with that name found.\n");
+
+        let not_lenient = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strict = true
+        "#).unwrap();
+        assert!(subject.process_book(&not_lenient, dir.path(), book).is_err());
+    }
+
+    #[test]
+    fn test_process_book_lenient_in_default_drafts() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strict = true
+            lenient-in-drafts = true
+        "#).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "".to_string(), "setup.md", Vec::new())));
+        book.push_item(BookItem::Chapter(Chapter::new("Ideas", "{{#path_for Setup}} {{#path_for Missing}}".to_string(), "guide/drafts/ideas.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let processed_book = subject.process_book(&config, Path::new("/"), book.clone()).unwrap();
+
+        assert_eq!(processed_book, book);
+
+        let mut published = Book::new();
+        published.push_item(BookItem::Chapter(Chapter::new("Ideas", "{{#path_for Missing}}".to_string(), "guide/ideas.md", Vec::new())));
+        assert!(subject.process_book(&config, Path::new("/"), published).is_err());
+    }
+
+    #[test]
+    fn test_options_lenient_in_drafts() {
+        let enabled = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            lenient-in-drafts = true
+        "#).unwrap();
+        let invalid = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            lenient-in-drafts = "(drafts"
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        let options = subject.options_from_config(&enabled);
+        assert!(options.lenient_in_drafts);
+        assert_eq!(options.draft_pattern.unwrap().as_str(), crate::DEFAULT_DRAFT_PATTERN);
+        assert!(!subject.options_from_config(&invalid).lenient_in_drafts);
+        assert!(!subject.options_from_config(&Config::default()).lenient_in_drafts);
+    }
+
+    #[test]
+    fn test_process_chapter_resolution_order() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "intro/getting-started.md"));
+        chapter_mapping.insert("installing".to_string(), chapter("Installing", "guide/setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.resolution_order = vec![Lookup::Name, Lookup::Path];
+        assert_eq!(subject.process_chapter("{{#path_for setup}} {{#path_for guide/setup}}", &chapter_mapping, &options).ok().unwrap(),
+            "/intro/getting-started.md /guide/setup.md".to_string());
+
+        options.resolution_order = vec![Lookup::Path, Lookup::Name];
+        assert_eq!(subject.process_chapter("{{#path_for setup}} {{#path_for Installing}}", &chapter_mapping, &options).ok().unwrap(),
+            "/guide/setup.md /guide/setup.md".to_string());
+
+        let received_chapter = subject.process_chapter("{{#path_for setup}}", &chapter_mapping, &processor_options("/")).ok().unwrap();
+        assert_eq!(received_chapter, "/intro/getting-started.md".to_string());
+        let received_error = subject.process_chapter("{{#path_for guide/setup}}", &chapter_mapping, &processor_options("/")).err().unwrap();
+        assert_eq!(received_error, ProcessorError::ChapterNotFound("guide/setup".to_string()));
+    }
+
+    #[test]
+    fn test_chapter_names_resolution_order_with_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.json");
+        std::fs::write(&manifest, r#"{"Foo": "https://example.com/foo", "API Reference": "https://docs.example.com/api"}"#).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Foo", "".to_string(), "foo.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.manifest = Some(manifest);
+        options.resolution_order = vec![Lookup::Name, Lookup::Manifest];
+
+        let chapter_mapping = subject.collect_chapter_names(&book, &options, &mut Vec::new()).unwrap();
+
+        let content = "{{#path_for Foo}} {{#path_for API Reference}}";
+        let received_chapter = subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap();
+        assert_eq!(received_chapter, "/foo.md https://docs.example.com/api".to_string());
+
+        options.resolution_order = vec![Lookup::Name];
+        let received_error = subject.process_chapter("{{#path_for API Reference}}", &chapter_mapping, &options).err().unwrap();
+        assert_eq!(received_error, ProcessorError::ChapterNotFound("api reference".to_string()));
+    }
+
+    #[test]
+    fn test_options_resolution_order() {
+        let configured = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            resolution-order = ["path", "alias", "name", "path"]
+        "#).unwrap();
+        let unknown = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            resolution-order = ["slug"]
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.options_from_config(&configured).resolution_order, vec![Lookup::Path, Lookup::Name]);
+        assert_eq!(subject.options_from_config(&unknown).resolution_order, vec![Lookup::Manifest, Lookup::Name]);
+        assert_eq!(subject.options_from_config(&Config::default()).resolution_order, vec![Lookup::Manifest, Lookup::Name]);
+    }
+
+    #[test]
+    fn test_process_options_resolves_files_against_root() {
+        let ctx = preprocessor_context(r#"
+            [output.html]
+            site-url = "/docs/"
+
+            [preprocessor.chapter-path]
+            diagnostics-file = "chapter-path.log"
+            manifest = "links.json"
+            emit-graph = "graph.dot"
+        "#, "html");
+
+        let subject = PathProcessor::new();
+
+        let options = subject.process_options(&ctx);
+
+        assert_eq!(options.site_path, "/docs/");
+        assert_eq!(options.book_root, PathBuf::from("/book"));
+        assert_eq!(options.diagnostics_file, Some(PathBuf::from("/book/chapter-path.log")));
+        assert_eq!(options.manifest, Some(PathBuf::from("/book/links.json")));
+        assert_eq!(options.graph_file, Some(PathBuf::from("/book/graph.dot")));
+    }
+
+    #[test]
+    fn test_process_options_defaults() {
+        let ctx = preprocessor_context("", "html");
+
+        let subject = PathProcessor::new();
+
+        let options = subject.process_options(&ctx);
+
+        assert_eq!(options.site_path, "/");
+        assert_eq!(options.diagnostics_file, None);
+        assert_eq!(options.manifest, None);
+        assert_eq!(options.src_dir, PathBuf::from("src"));
+        assert!(!options.strict_mode);
+    }
+
+    #[test]
+    fn test_chapter_urls_from_context() {
+        let ctx = preprocessor_context(r#"
+            [output.html]
+            site-url = "/docs/"
+        "#, "html");
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "".to_string(), "intro.md", Vec::new())));
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.chapter_urls(&ctx, &book), vec![("Intro".to_string(), "/docs/intro.md".to_string())]);
+    }
+
+    #[test]
+    fn test_supports_renderer() {
+        let subject = PathProcessor::new();
+
+        assert!(subject.supports_renderer(&preprocessor_context("", "html").renderer));
+        assert!(!subject.supports_renderer(&preprocessor_context("", "epub").renderer));
+        assert!(!subject.supports_renderer(&preprocessor_context("", "markdown").renderer));
+    }
+
+    // A context for a book at `/book` with the given `book.toml` contents, as mdBook would pass it.
+    fn preprocessor_context(config: &str, renderer: &str) -> PreprocessorContext {
+        serde_json::from_value(serde_json::json!({
+            "root": "/book",
+            "config": Config::from_str(config).unwrap(),
+            "renderer": renderer,
+            "mdbook_version": mdbook::MDBOOK_VERSION
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_process_chapter_encoding() {
+        let content = "{{#path_for Notes#a b|c}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("notes".to_string(), chapter("Notes", "release notes/v1#2 [draft]?.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.encoding = UrlEncoding::Strict;
+        assert_eq!(subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap(),
+            "/release%20notes/v1%232%20%5Bdraft%5D%3F.md#a%20b%7Cc".to_string());
+
+        options.encoding = UrlEncoding::Lenient;
+        assert_eq!(subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap(),
+            "/release%20notes/v1%232%20[draft]%3F.md#a%20b|c".to_string());
+
+        options.encoding = UrlEncoding::Off;
+        assert_eq!(subject.process_chapter(content, &chapter_mapping, &options).ok().unwrap(),
+            "/release notes/v1#2 [draft]?.md#a b|c".to_string());
+    }
+
+    #[test]
+    fn test_process_chapter_strict_encoding_keeps_allowed_characters() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("café".to_string(), chapter("Café", "guide/caf\u{e9}_(1)~v2.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.encoding = UrlEncoding::Strict;
+
+        let received_chapter = subject.process_chapter("{{#path_for Café#q?x=1}}", &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "/guide/caf%C3%A9_(1)~v2.md#q?x=1".to_string());
+    }
+
+    #[test]
+    fn test_options_encoding() {
+        let strict = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            encoding = "strict"
+        "#).unwrap();
+        let unknown = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            encoding = "rfc"
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.options_from_config(&strict).encoding, UrlEncoding::Strict);
+        assert_eq!(subject.options_from_config(&unknown).encoding, UrlEncoding::Off);
+        assert_eq!(subject.options_from_config(&Config::default()).encoding, UrlEncoding::Off);
+    }
+
+    #[test]
+    fn test_normalize_chapter_uses_chapter_names() {
+        let content = "{{#path_for getting started#install}} {{#short_path_for GETTING STARTED}} {{#path_for Getting Started}} {{#path_for widget setup}} \\{{#path_for getting started}} {{#path_for @getting-started}} {{#path_for Missing}}";
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("getting started".to_string(), chapter("Getting Started", "getting-started.md"));
+        chapter_mapping.insert("widget setup".to_string(), chapter("Widget\u{2122} Setup", "widget.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.ignore_chars = vec!['\u{2122}'];
+
+        let (normalized_content, changes) = subject.normalize_chapter(content, &chapter_mapping, &options);
+
+        assert_eq!(normalized_content, "{{#path_for Getting Started#install}} {{#short_path_for Getting Started}} {{#path_for Getting Started}} {{#path_for Widget\u{2122} Setup}} \\{{#path_for getting started}} {{#path_for @getting-started}} {{#path_for Missing}}");
+        assert_eq!(changes, vec![
+            ReferenceFix { line: 1, from: "getting started".to_string(), to: "Getting Started".to_string() },
+            ReferenceFix { line: 1, from: "GETTING STARTED".to_string(), to: "Getting Started".to_string() },
+            ReferenceFix { line: 1, from: "widget setup".to_string(), to: "Widget\u{2122} Setup".to_string() }
+        ]);
+    }
+
+    #[test]
+    fn test_footnote_ids() {
+        let content = "# Setup\n\nSee the caveat[^caveat] and[^Long Note].\n\n[^caveat]: Only on Linux.\n\n[^Long Note]: Details.\n";
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.footnote_ids(content), vec!["caveat".to_string(), "Long Note".to_string()]);
+    }
+
+    #[test]
+    fn test_process_chapter_validate_footnote_anchors() {
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Setup", "# Setup\n\nOnly on Linux[^linux].\n\n[^linux]: And BSD.\n".to_string(), "setup.md", Vec::new())));
+
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("setup".to_string(), chapter("Setup", "setup.md"));
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.validate_anchors = true;
+        options.anchors = subject.collect_anchors(&book, &options);
+
+        let received_chapter = subject.process_chapter("{{#path_for Setup#linux}} {{#path_for Setup#setup}}", &chapter_mapping, &options).ok().unwrap();
+
+        assert_eq!(received_chapter, "/setup.md#linux /setup.md#setup".to_string());
+
+        let received_error = subject.process_chapter("{{#path_for Setup#bsd}}", &chapter_mapping, &options).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::AnchorNotFound("Setup".to_string(), "bsd".to_string()));
+    }
+
+    #[test]
+    fn test_heading_ids_algorithms() {
+        let content = "# Setup\n\n## Using [`foo`](foo.md) & ~~bar~~\n\n## Install `foo`\n";
+
+        let subject = PathProcessor::new();
+
+        let mut options = processor_options("/");
+        options.id_algorithm = IdAlgorithm::V04;
+        let mut received_ids: Vec<String> = subject.heading_ids(content, &options).keys().cloned().collect();
+        received_ids.sort();
+        assert_eq!(received_ids, vec!["install-foo", "setup", "using-a-hreffoohtmlfooa--delbardel"]);
+
+        options.id_algorithm = IdAlgorithm::Latest;
+        let mut received_ids: Vec<String> = subject.heading_ids(content, &options).keys().cloned().collect();
+        received_ids.sort();
+        assert_eq!(received_ids, vec!["install-foo", "setup", "using-foo--bar"]);
+    }
+
+    #[test]
+    fn test_v04_heading_ids_match_mdbook() {
+        for content in &["Install <code>foo</code>", "<em>Tom</em> &amp; <strong>Jerry</strong>", "  What&#39;s new?  ", "Ünïcode_and-dashes"] {
+            assert_eq!(IdAlgorithm::V04.heading_id(content), mdbook::utils::id_from_content(content));
+        }
+    }
+
+    #[test]
+    fn test_options_id_algorithm() {
+        let latest = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            id-algorithm = "latest"
+        "#).unwrap();
+        let unknown = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            id-algorithm = "v0.3"
+        "#).unwrap();
+
+        let subject = PathProcessor::new();
+
+        assert_eq!(subject.options_from_config(&latest).id_algorithm, IdAlgorithm::Latest);
+        assert_eq!(subject.options_from_config(&unknown).id_algorithm, IdAlgorithm::V04);
+        assert_eq!(subject.options_from_config(&Config::default()).id_algorithm, IdAlgorithm::V04);
+    }
+
+    #[test]
+    fn test_emit_graph_survives_build() {
+        let dir = fixture_book_dir("emit-graph = \"chapter-graph.dot\"\n");
+
+        build_fixture_book(dir.path());
+
+        assert!(dir.path().join("book").join("intro.html").exists());
+        assert_eq!(std::fs::read_to_string(dir.path().join("chapter-graph.dot")).unwrap(), concat!(
+            "digraph chapters {\n",
+            "    \"intro.md\" [label=\"Intro\"];\n",
+            "    \"setup.md\" [label=\"Setup\"];\n",
+            "    \"intro.md\" -> \"setup.md\" [label=\"1\"];\n",
+            "}\n"
+        ));
+    }
+
+    // A book on disk with two chapters, Intro referencing Setup, and the given chapter-path config.
+    fn fixture_book_dir(config: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        // mdBook runs configured preprocessors as commands too. Pointing it at one that doesn't exist
+        // makes it skip that, so only the preprocessor `build_fixture_book` registers runs.
+        std::fs::write(dir.path().join("book.toml"), format!(
+            "[book]\ntitle = \"Fixture\"\n\n[preprocessor.chapter-path]\ncommand = \"mdbook-chapter-path-not-installed\"\n{}", config
+        )).unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src").join("SUMMARY.md"), "- [Intro](intro.md)\n- [Setup](setup.md)\n").unwrap();
+        std::fs::write(dir.path().join("src").join("intro.md"), "# Intro\n\nSee [setup]({{#path_for setup}}).\n").unwrap();
+        std::fs::write(dir.path().join("src").join("setup.md"), "# Setup\n").unwrap();
+        dir
+    }
+
+    // Runs a full `mdbook build`, html renderer included, with this preprocessor.
+    fn build_fixture_book(root: &Path) {
+        let mut md = mdbook::MDBook::load(root).unwrap();
+        md.with_preprocessor(PathProcessor::new());
+        md.build().unwrap();
+    }
+
+    #[test]
+    fn test_parse_directives_multiple_anchors() {
+        let directives = crate::parse_directives("{{#path_for a#b#c}}");
+
+        assert_eq!(directives.len(), 1);
+        assert_eq!(directives[0].name, "a");
+        assert_eq!(directives[0].anchor, Some("b#c"));
+    }
+
+    #[test]
+    fn test_process_chapter_multiple_anchors() {
+        let mut chapter_mapping: ChapterMap = HashMap::new();
+        chapter_mapping.insert("a".to_string(), chapter("A", "a.md"));
+
+        let subject = PathProcessor::new();
+
+        let received_error = subject.process_chapter("{{#path_for A}} {{#path_for a#b#c}}", &chapter_mapping, &processor_options("/")).err().unwrap();
+
+        assert_eq!(received_error, ProcessorError::MultipleAnchors("a#b#c".to_string()));
+        assert_eq!(subject.process_chapter("\\{{#path_for a#b#c}}", &chapter_mapping, &processor_options("/")).ok().unwrap(), "{{#path_for a#b#c}}".to_string());
+    }
+
+    #[test]
+    fn test_options_config_warnings() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            output-format = "json"
+            list-style = "compact"
+            encoding = "rfc"
+        "#).unwrap();
+
+        let options = PathProcessor::new().options_from_config(&config);
+
+        assert_eq!(options.output_format, OutputFormat::Json);
+        assert_eq!(options.config_warnings, vec![
+            "Unknown encoding 'rfc', falling back to none".to_string(),
+            "Unknown list-style 'compact', falling back to tight".to_string()
+        ]);
+        assert!(PathProcessor::new().options_from_config(&Config::default()).config_warnings.is_empty());
+    }
+
+    #[test]
+    fn test_options_config_warnings_for_wrong_types() {
+        let config = Config::from_str(r#"
+            [preprocessor.chapter-path]
+            strict = "yes"
+            ignore-chars = 5
+            encoding = 3
+            strip-number-prefix = 1
+            compat = "hugo"
+        "#).unwrap();
+
+        let options = PathProcessor::new().options_from_config(&config);
+
+        assert!(!options.strict_mode);
+        assert!(options.ignore_chars.is_empty());
+        assert_eq!(options.encoding, UrlEncoding::Off);
+        assert!(options.number_prefix.is_none());
+        assert_eq!(options.compat, None);
+        assert_eq!(options.config_warnings, vec![
+            "Invalid strict value \"yes\", expected true or false; ignoring it".to_string(),
+            "Invalid ignore-chars value 5, expected a string or an array of strings; ignoring it".to_string(),
+            "Invalid encoding value 3, expected a string; ignoring it".to_string(),
+            "Unknown compat 'hugo', ignoring it".to_string(),
+            "Invalid strip-number-prefix value 1, expected true, false or a pattern; ignoring it".to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_process_book_reports_config_warnings_in_output_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("chapter-path.log");
+        let config = Config::from_str(&format!(r#"
+            [preprocessor.chapter-path]
+            output-format = "json"
+            list-style = "compact"
+            profile = true
+            diagnostics-file = {:?}
+        "#, log.to_str().unwrap())).unwrap();
+
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new("Intro", "".to_string(), "intro.md", Vec::new())));
+
+        PathProcessor::new().process_book(&config, dir.path(), book).unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&log).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], serde_json::json!({ "level": "warning", "message": "Unknown list-style 'compact', falling back to tight" }));
+        assert_eq!(lines[1]["level"], "note");
+        assert!(lines[1]["message"].as_str().unwrap().starts_with("chapter-path processed 1 chapters in "));
+    }
+
+    fn chapter(name: &str, path: &str) -> ChapterEntry {
+        ChapterEntry { name: name.to_string(), path: PathBuf::from(path), url: None }
     }
 
     fn processor_options(site_path: &str) -> PathProcessorOptions {
         PathProcessorOptions {
             site_path: site_path.to_string(),
-            strict_mode: false
+            relative_links: false,
+            strict_mode: false,
+            skip_marker: crate::DEFAULT_SKIP_MARKER.to_string(),
+            output_format: OutputFormat::Plain,
+            ignore_chars: Vec::new(),
+            path_hint_resolution: PathHintResolution::Unique,
+            variables: HashMap::new(),
+            profile: false,
+            missing_message: crate::DEFAULT_MISSING_MESSAGE.to_string(),
+            src_dir: PathBuf::from("src"),
+            book_root: PathBuf::new(),
+            mtime_format: crate::DEFAULT_MTIME_FORMAT.to_string(),
+            default_target: Some("Introduction".to_string()),
+            default_target_keyword: None,
+            warn_case_mismatch: false,
+            table_safe: false,
+            encoding: UrlEncoding::Off,
+            anchor_prefix: String::new(),
+            short_url_template: None,
+            process_part_titles: false,
+            diagnostics_file: None,
+            compat: None,
+            backslash_check: CheckLevel::Off,
+            escape_check: CheckLevel::Warn,
+            manifest: None,
+            backlinks: HashMap::new(),
+            number_prefix: None,
+            list_style: ListStyle::Tight,
+            empty_result: EmptyResult::Empty,
+            validate_anchors: false,
+            id_algorithm: IdAlgorithm::V04,
+            anchors: HashMap::new(),
+            ambiguous_anchor: CheckLevel::Warn,
+            missing_chapter: MissingChapter::Error,
+            report_only: false,
+            graph_file: None,
+            config_warnings: Vec::new(),
+            lenient_in_drafts: false,
+            draft_pattern: None,
+            resolution_order: crate::DEFAULT_RESOLUTION_ORDER.to_vec()
         }
     }
 }
\ No newline at end of file
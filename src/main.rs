@@ -1,49 +1,200 @@
 use clap::{App, Arg, ArgMatches, SubCommand};
 
-use mdbook_chapter_path::PathProcessor;
-use mdbook::preprocess::{Preprocessor, CmdPreprocessor};
+use mdbook_chapter_path::{FixedChapter, PathProcessor};
+use mdbook::MDBook;
+use mdbook::book::Book;
+use mdbook::preprocess::{Preprocessor, CmdPreprocessor, PreprocessorContext};
 use mdbook::errors::Error;
-use std::{io, process};
+use std::{fs, io, process};
+use std::path::Path;
+
+// What to do when mdbook's version differs from the one we were built against.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum VersionCheck {
+    Warn,
+    Off,
+    Error
+}
 
 pub fn make_app() -> App<'static, 'static> {
     App::new("mdbook-chapter-Path")
         .about("A preprocessor that provides paths to chapters based on the name of the chapter.")
+        .arg(
+            Arg::with_name("suppress-version-warning")
+                .long("suppress-version-warning")
+                .help("Don't warn when mdbook's version differs from the one this preprocessor was built against"),
+        )
         .subcommand(
             SubCommand::with_name("supports")
                 .arg(Arg::with_name("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            SubCommand::with_name("fix")
+                .arg(Arg::with_name("dir").default_value(".").help("Root directory of the book"))
+                .arg(Arg::with_name("write").long("write").help("Rewrite the chapter files instead of only listing the fixes"))
+                .about("Correct references to misspelled chapter names in the book's source files"),
+        )
+        .subcommand(
+            SubCommand::with_name("normalize")
+                .arg(Arg::with_name("dir").default_value(".").help("Root directory of the book"))
+                .arg(Arg::with_name("write").long("write").help("Rewrite the chapter files instead of only listing the changes"))
+                .about("Rewrite references to use chapter names exactly as written in SUMMARY.md"),
+        )
 }
 
 fn main() {
     let matches = make_app().get_matches();
-    let preprocessor = PathProcessor;
+    let preprocessor = PathProcessor::new();
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
     }
-    if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
+    if let Some(sub_args) = matches.subcommand_matches("fix") {
+        let dir = sub_args.value_of("dir").expect("Has default value");
+        match handle_fix(&preprocessor, Path::new(dir), sub_args.is_present("write")) {
+            Ok(report) => {
+                print!("{}", report);
+                process::exit(0);
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(sub_args) = matches.subcommand_matches("normalize") {
+        let dir = sub_args.value_of("dir").expect("Has default value");
+        match handle_normalize(&preprocessor, Path::new(dir), sub_args.is_present("write")) {
+            Ok(report) => {
+                print!("{}", report);
+                process::exit(0);
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    // Until the context is read, the configured output format isn't known.
+    let (ctx, book) = match CmdPreprocessor::parse_input(io::stdin()) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    // Every error has already been reported, in the configured output format.
+    if handle_preprocessing(&preprocessor, &ctx, book, matches.is_present("suppress-version-warning")).is_err() {
+        process::exit(1);
     }
 }
 
-fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
-
-    if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
-        eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
-             but we're being called from version {}",
-            pre.name(),
-            mdbook::MDBOOK_VERSION,
-            ctx.mdbook_version
-        );
+// Reports any error through the preprocessor before returning it, so it's printed in the configured
+// output format like the preprocessor's own diagnostics.
+fn handle_preprocessing(pre: &PathProcessor, ctx: &PreprocessorContext, book: Book, suppress_version_warning: bool) -> Result<(), Error> {
+    let mut warnings: Vec<String> = Vec::new();
+    let version_check = if suppress_version_warning { VersionCheck::Off } else { version_check_from_config(ctx, &mut warnings) };
+    match check_version(version_check, pre.name(), &ctx.mdbook_version) {
+        Ok(Some(warning)) => warnings.push(warning),
+        Ok(None) => {},
+        Err(e) => {
+            pre.report_error(ctx, &e.to_string());
+            return Err(e);
+        }
     }
 
-    let processed_book = pre.run(&ctx, book)?;
-    serde_json::to_writer(io::stdout(), &processed_book)?;
+    // `run` starts the diagnostics file afresh, so these are reported after it.
+    let processed = pre.run(ctx, book);
+    for warning in warnings {
+        pre.report_warning(ctx, &warning);
+    }
+    let processed_book = processed?;
+    if let Err(e) = serde_json::to_writer(io::stdout(), &processed_book) {
+        pre.report_error(ctx, &e.to_string());
+        return Err(e.into());
+    }
     Ok(())
 }
 
+fn version_check_from_config(ctx: &PreprocessorContext, warnings: &mut Vec<String>) -> VersionCheck {
+    if let Some(config) = ctx.config.get_preprocessor("chapter-path") {
+        if let Some(toml::value::Value::String(value)) = config.get("version-check") {
+            match value.as_str() {
+                "warn" => return VersionCheck::Warn,
+                "off" => return VersionCheck::Off,
+                "error" => return VersionCheck::Error,
+                _ => warnings.push(format!("Unknown version-check '{}', falling back to warn", value))
+            }
+        }
+    }
+    VersionCheck::Warn
+}
+
+// Returns the warning to report, if any, or an error when mismatches are fatal.
+fn check_version(check: VersionCheck, name: &str, mdbook_version: &str) -> Result<Option<String>, Error> {
+    if check == VersionCheck::Off || mdbook_version == mdbook::MDBOOK_VERSION {
+        return Ok(None);
+    }
+
+    let message = format!(
+        "The {} plugin was built against version {} of mdbook, \
+         but we're being called from version {}",
+        name,
+        mdbook::MDBOOK_VERSION,
+        mdbook_version
+    );
+
+    match check {
+        VersionCheck::Error => Err(Error::msg(message)),
+        _ => Ok(Some(message))
+    }
+}
+
+// Lists (and with `write`, applies) fixes for misspelled chapter references. Returns the report to print.
+fn handle_fix(pre: &PathProcessor, dir: &Path, write: bool) -> Result<String, Error> {
+    let md = MDBook::load(dir)?;
+    let fixed_chapters = pre.fix_references(&md.root, &md.config, &md.book)?;
+    let mut report = rewrite_report(&md, &fixed_chapters, write)?;
+
+    if fixed_chapters.is_empty() {
+        report.push_str("No references to fix.\n");
+    } else if !write {
+        report.push_str("Dry run: re-run with --write to apply these fixes.\n");
+    }
+    Ok(report)
+}
+
+// Like `handle_fix`, for references whose casing (or ignored characters) differs from the chapter name.
+fn handle_normalize(pre: &PathProcessor, dir: &Path, write: bool) -> Result<String, Error> {
+    let md = MDBook::load(dir)?;
+    let normalized_chapters = pre.normalize_references(&md.root, &md.config, &md.book)?;
+    let mut report = rewrite_report(&md, &normalized_chapters, write)?;
+
+    if normalized_chapters.is_empty() {
+        report.push_str("No references to normalize.\n");
+    } else if !write {
+        report.push_str("Dry run: re-run with --write to apply these changes.\n");
+    }
+    Ok(report)
+}
+
+// One line per changed reference. With `write`, the chapter files are rewritten too.
+fn rewrite_report(md: &MDBook, chapters: &[FixedChapter], write: bool) -> Result<String, Error> {
+    let src_dir = md.root.join(&md.config.book.src);
+
+    let mut report = String::new();
+    for fixed in chapters {
+        let file = src_dir.join(&fixed.source_path);
+        for fix in &fixed.fixes {
+            report.push_str(&format!("{}:{}: '{}' -> '{}'\n", file.display(), fix.line, fix.from, fix.to));
+        }
+        if write {
+            fs::write(&file, &fixed.content)?;
+        }
+    }
+    Ok(report)
+}
+
 fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args.value_of("renderer").expect("Required argument");
     let supported = pre.supports_renderer(&renderer);
@@ -52,4 +203,112 @@ fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     } else {
         process::exit(1);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use mdbook_chapter_path::PathProcessor;
+    use crate::{check_version, handle_fix, handle_normalize, VersionCheck};
+
+    #[test]
+    fn test_check_version_warns_on_mismatch_by_default() {
+        let warning = check_version(VersionCheck::Warn, "chapter-path", "0.0.1").unwrap();
+
+        assert_eq!(warning, Some(format!(
+            "The chapter-path plugin was built against version {} of mdbook, but we're being called from version 0.0.1",
+            mdbook::MDBOOK_VERSION
+        )));
+    }
+
+    #[test]
+    fn test_check_version_can_be_suppressed() {
+        let warning = check_version(VersionCheck::Off, "chapter-path", "0.0.1").unwrap();
+
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn test_check_version_can_be_fatal() {
+        assert!(check_version(VersionCheck::Error, "chapter-path", "0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_check_version_matching_version() {
+        let warning = check_version(VersionCheck::Error, "chapter-path", mdbook::MDBOOK_VERSION).unwrap();
+
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn test_handle_fix_dry_run_leaves_files_alone() {
+        let book = fixture_book();
+
+        let report = handle_fix(&PathProcessor::new(), book.path(), false).unwrap();
+
+        let intro = book.path().join("src").join("intro.md");
+        assert_eq!(report, format!(
+            "{}:3: 'Instalation' -> 'Installation'\nDry run: re-run with --write to apply these fixes.\n",
+            intro.display()
+        ));
+        assert_eq!(fs::read_to_string(intro).unwrap(), "# Intro\n\nSee [installing]({{#path_for Instalation}}).\n");
+    }
+
+    #[test]
+    fn test_handle_fix_write_corrects_files() {
+        let book = fixture_book();
+
+        handle_fix(&PathProcessor::new(), book.path(), true).unwrap();
+
+        let intro = fs::read_to_string(book.path().join("src").join("intro.md")).unwrap();
+        assert_eq!(intro, "# Intro\n\nSee [installing]({{#path_for Installation}}).\n");
+        let report = handle_fix(&PathProcessor::new(), book.path(), false).unwrap();
+        assert_eq!(report, "No references to fix.\n");
+    }
+
+    #[test]
+    fn test_handle_normalize_dry_run_leaves_files_alone() {
+        let book = fixture_book();
+        write(book.path(), "src/installation.md", "# Installation\n\nBack to the [intro]({{#path_for INTRO}}) or [setup]({{#path_for installation#setup}}).\n");
+
+        let report = handle_normalize(&PathProcessor::new(), book.path(), false).unwrap();
+
+        let installation = book.path().join("src").join("installation.md");
+        assert_eq!(report, format!(
+            "{0}:3: 'INTRO' -> 'Intro'\n{0}:3: 'installation' -> 'Installation'\nDry run: re-run with --write to apply these changes.\n",
+            installation.display()
+        ));
+        assert_eq!(fs::read_to_string(installation).unwrap(), "# Installation\n\nBack to the [intro]({{#path_for INTRO}}) or [setup]({{#path_for installation#setup}}).\n");
+    }
+
+    #[test]
+    fn test_handle_normalize_write_canonicalizes_files() {
+        let book = fixture_book();
+        write(book.path(), "src/installation.md", "# Installation\n\nBack to the [intro]({{#path_for INTRO}}) or [setup]({{#path_for installation#setup}}).\n");
+
+        handle_normalize(&PathProcessor::new(), book.path(), true).unwrap();
+
+        let installation = fs::read_to_string(book.path().join("src").join("installation.md")).unwrap();
+        assert_eq!(installation, "# Installation\n\nBack to the [intro]({{#path_for Intro}}) or [setup]({{#path_for Installation#setup}}).\n");
+        let intro = fs::read_to_string(book.path().join("src").join("intro.md")).unwrap();
+        assert_eq!(intro, "# Intro\n\nSee [installing]({{#path_for Instalation}}).\n");
+        let report = handle_normalize(&PathProcessor::new(), book.path(), false).unwrap();
+        assert_eq!(report, "No references to normalize.\n");
+    }
+
+    fn fixture_book() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "book.toml", "[book]\ntitle = \"Fixture\"\n");
+        write(dir.path(), "src/SUMMARY.md", "# Summary\n\n- [Intro](intro.md)\n- [Installation](installation.md)\n");
+        write(dir.path(), "src/intro.md", "# Intro\n\nSee [installing]({{#path_for Instalation}}).\n");
+        write(dir.path(), "src/installation.md", "# Installation\n");
+        dir
+    }
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}